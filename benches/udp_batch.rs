@@ -0,0 +1,160 @@
+// Packets per second through loopback: one syscall per datagram, batched syscalls and segmentation offload.
+// Run with `cargo bench --bench udp_batch`.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use dserve::{
+    definitions::{OffloadSocket, SocketConfig, Transport},
+    implementations::bind_socket,
+};
+
+const DATAGRAM_SIZE: usize = 100;
+const ROUNDS: usize = 2000;
+const BURST: usize = 64;
+
+fn sockets() -> io::Result<(UdpSocket, UdpSocket, SocketAddr)> {
+    let config = SocketConfig {
+        recv_buffer_size: Some(8 * 1024 * 1024),
+        ..Default::default()
+    };
+    let receiver = config.bind("127.0.0.1:0")?;
+    let sender = bind_socket("127.0.0.1:0")?;
+    let to = receiver.local_addr()?;
+    Ok((sender, receiver, to))
+}
+
+fn drain(receiver: &UdpSocket) {
+    let mut buf = [0u8; 2048];
+    while UdpSocket::recv_from(receiver, &mut buf).is_ok() {}
+}
+
+fn report(name: &str, packets: usize, elapsed: Duration) {
+    println!(
+        "{:<24} {:>12.0} packets/s",
+        name,
+        packets as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn bench_send(batched: bool) -> io::Result<()> {
+    let (sender, receiver, to) = sockets()?;
+    let burst: Vec<(Vec<u8>, SocketAddr)> = vec![(vec![7u8; DATAGRAM_SIZE], to); BURST];
+
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        if batched {
+            let mut sent = 0;
+            while sent < burst.len() {
+                sent += sender.send_batch(&burst[sent..])?;
+            }
+        } else {
+            for (datagram, to) in &burst {
+                Transport::send_to(&sender, datagram, *to)?;
+            }
+        }
+        elapsed += start.elapsed();
+
+        drain(&receiver);
+    }
+
+    let name = if batched { "send_batch" } else { "send_to" };
+    report(name, ROUNDS * BURST, elapsed);
+    Ok(())
+}
+
+fn bench_recv(batched: bool) -> io::Result<()> {
+    let (sender, receiver, to) = sockets()?;
+    let datagram = [7u8; DATAGRAM_SIZE];
+    let mut bufs = vec![vec![0u8; 2048]; 32];
+    let mut received = Vec::new();
+    let mut buf = [0u8; 2048];
+
+    let mut packets = 0;
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        for _ in 0..BURST {
+            UdpSocket::send_to(&sender, &datagram, to)?;
+        }
+
+        let start = Instant::now();
+        loop {
+            if batched {
+                match receiver.recv_batch(&mut bufs, &mut received) {
+                    Ok(()) => packets += received.len(),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            } else {
+                match Transport::recv_from(&receiver, &mut buf) {
+                    Ok(_) => packets += 1,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        elapsed += start.elapsed();
+    }
+
+    let name = if batched { "recv_batch" } else { "recv_from" };
+    report(name, packets, elapsed);
+    Ok(())
+}
+
+// Equal-sized bursts to one peer, the case segmentation offload coalesces
+fn bench_offload() -> io::Result<()> {
+    let config = SocketConfig {
+        recv_buffer_size: Some(8 * 1024 * 1024),
+        ..Default::default()
+    };
+    let receiver = OffloadSocket::bind("127.0.0.1:0", &config)?;
+    let sender = OffloadSocket::bind("127.0.0.1:0", &config)?;
+    let to = receiver.local_addr()?;
+    println!(
+        "offload: gso {}, gro {}",
+        sender.supports_gso(),
+        receiver.supports_gro()
+    );
+
+    let burst: Vec<(Vec<u8>, SocketAddr)> = vec![(vec![7u8; DATAGRAM_SIZE], to); BURST];
+    let mut bufs = vec![vec![0u8; 2048]; 32];
+    let mut received = Vec::new();
+
+    let mut packets = 0;
+    let mut send_elapsed = Duration::ZERO;
+    let mut recv_elapsed = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        let mut sent = 0;
+        while sent < burst.len() {
+            sent += sender.send_batch(&burst[sent..])?;
+        }
+        send_elapsed += start.elapsed();
+
+        let start = Instant::now();
+        loop {
+            match receiver.recv_batch(&mut bufs, &mut received) {
+                Ok(()) => packets += received.len(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        recv_elapsed += start.elapsed();
+    }
+
+    report("send_batch (offload)", ROUNDS * BURST, send_elapsed);
+    report("recv_batch (offload)", packets, recv_elapsed);
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    bench_send(false)?;
+    bench_send(true)?;
+    bench_recv(false)?;
+    bench_recv(true)?;
+    bench_offload()
+}
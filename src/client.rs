@@ -1,18 +1,52 @@
-use dserve::definitions::def;
-use std::time::Duration;
+use std::{
+    env,
+    sync::{Arc, Mutex},
+};
+
+use dserve::{
+    definitions::def,
+    implementations::{bind_socket, init_logging},
+};
+use tracing::info;
 
 fn main() -> std::io::Result<()> {
-    let mut client = def::NetworkProtocol::new("127.0.0.1:3801")?;
+    init_logging();
+
+    let mut args = env::args().skip(1);
+    let server_addr = args.next().unwrap_or_else(|| "127.0.0.1:3800".to_string());
+    // Pass [::]:3801 to reach servers over either IP version
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:3801".to_string());
+
+    let socket = bind_socket(addr)?;
+    let local_addr = socket.local_addr()?;
+
+    // Starts as a perfect link; type `on` and other commands into stdin to degrade it
+    let conditions = Arc::new(Mutex::new(def::NetworkConditions::default()));
+    def::NetworkConditions::spawn_console(conditions.clone());
 
-    println!("Client started on 127.0.0.1:3801");
+    let mut client =
+        def::NetworkProtocol::with_transport(def::ConditionedTransport::new(socket, conditions, 0));
+
+    info!("Client started on {}", local_addr);
+
+    // Packets for offline inspection, and in development builds the keys to decrypt them
+    if let Ok(path) = env::var("DSERVE_PCAP") {
+        client.capture_to(&path)?;
+        info!("Capturing packets to {}", path);
+    }
+    #[cfg(feature = "keylog")]
+    if let Ok(path) = env::var("DSERVE_KEYLOG") {
+        client.keylog_to(&path)?;
+        info!("Writing session keys to {}", path);
+    }
 
-    client.connect("127.0.0.1:3801")?;
+    client.connect(server_addr.as_str())?;
 
-    println!("Attempting to connect to server...");
+    info!("Attempting to connect to {}...", server_addr);
 
     loop {
+        // Sleep until there is traffic or a timer is due
+        client.wait(client.next_wakeup())?;
         client.update()?;
-        // Using 60fps update rate
-        std::thread::sleep(Duration::from_millis(16));
     }
 }
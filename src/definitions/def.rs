@@ -1,23 +1,113 @@
-use enums::ProtocolState;
-use ring::aead;
+use enums::{
+    Codec, DeliveryEvent, DisconnectReason, PacketKind, Payload, ProtocolEvent, ProtocolState,
+    RendezvousMessage,
+};
+use rand::rngs::StdRng;
+use ring::{aead, agreement, hmac};
 use std::{
-    collections::{HashMap, VecDeque},
-    net::UdpSocket,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{self, BufWriter},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
 use crate::enums;
 
+#[cfg(feature = "tokio")]
+use enums::EndpointCommand;
+#[cfg(feature = "tokio")]
+use tokio::sync::mpsc;
+
 #[derive(Debug, Clone)]
 pub struct Packet {
+    pub kind: PacketKind,
+    pub connection_id: u64,
     pub sequence: u32,
     pub ack: u32,
     pub ack_bits: u32,
-    pub data: Vec<u8>,
+    pub message_id: u32,
+    // Whether `data` is compressed with the session's codec for this kind of message
+    pub compressed: bool,
+    // Whether `data` is a position in the session's compression stream followed by the message
+    pub streamed: bool,
+    pub data: Payload,
     pub timestamp: Instant,
     pub attempts: u8,
 }
 
+// Recycles byte buffers so that the packet path doesn't allocate for every datagram
+pub struct BufferPool {
+    pub buffers: Mutex<Vec<Vec<u8>>>,
+    // Capacity new buffers start with; buffers that grew past `max_capacity` are freed instead
+    pub capacity: usize,
+    pub max_capacity: usize,
+    pub max_buffers: usize,
+}
+
+// Buffer lent out by a `BufferPool`, which gets it back when this is dropped
+pub struct PooledBuffer {
+    pub buffer: Vec<u8>,
+    pub pool: Arc<BufferPool>,
+}
+
+// A transmitted datagram that is still waiting to be acked
+#[derive(Debug, Clone)]
+pub struct SentPacket {
+    pub message_id: u32,
+    pub reliable: bool,
+    pub sent_at: Instant,
+}
+
+// Counters kept by a session over its lifetime. `Session::stats` fills in the rest from the
+// session's current state.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub retransmissions: u64,
+    // Sent packets that were acked, or that never will be
+    pub packets_acked: u64,
+    pub packets_lost: u64,
+    // Share of sent packets that went unacked
+    pub loss_rate: f64,
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    pub window_size: u32,
+    pub incoming_queue: usize,
+    pub outgoing_queue: usize,
+    // Reliable messages not yet acked
+    pub unacked_messages: usize,
+    // Datagrams for the connection that failed authentication
+    pub decrypt_failures: u64,
+    // Packets and reliable messages received more than once
+    pub duplicates: u64,
+}
+
+// Totals over every connection an endpoint has had, including closed ones
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EndpointStats {
+    // Open right now
+    pub connections: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub retransmissions: u64,
+    pub packets_acked: u64,
+    pub packets_lost: u64,
+    pub decrypt_failures: u64,
+    pub duplicates: u64,
+}
+
 pub struct PacketBuffer {
     pub incoming: VecDeque<Packet>,
     pub outgoing: VecDeque<Packet>,
@@ -33,19 +123,543 @@ pub struct CongestionControl {
     pub last_window_decrease: Instant,
 }
 
+// Which codecs a connection offers at handshake, per channel and in order of preference. The
+// accepting side picks the first one it also lists, or `Codec::None`.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub reliable: Vec<Codec>,
+    pub unreliable: Vec<Codec>,
+    // Messages shorter than this are sent as they are
+    pub threshold: usize,
+    // Used by LZ4 and zstd when the peer has the same one
+    pub dictionary: Option<Arc<CompressionDictionary>>,
+    // Compress reliable messages as one zlib stream, if the peer agrees. Repeated structures
+    // then compress well across messages, at the cost of delivering them in order.
+    pub streaming: bool,
+}
+
+// What the two sides agreed on during the handshake; nothing is compressed before then
+#[derive(Debug, Clone, Default)]
+pub struct NegotiatedCompression {
+    pub reliable: Codec,
+    pub unreliable: Codec,
+    pub dictionary: Option<Arc<CompressionDictionary>>,
+    pub streaming: bool,
+}
+
+// A zlib stream per direction for the reliable channel. Each message is sync-flushed, so it can
+// be decompressed once it and every message before it have arrived.
+pub struct CompressionStream {
+    pub compress: flate2::Compress,
+    pub decompress: flate2::Decompress,
+    pub next_sent: u32,
+    pub next_received: u32,
+    // Messages that arrived ahead of an earlier one, still compressed
+    pub pending: BTreeMap<u32, Packet>,
+    pub pending_bytes: usize,
+}
+
+// Appends to `output`, but fails rather than grow it past `remaining` more bytes, which puts a
+// ceiling on what a decompressor can produce
+pub struct LimitedWriter<'a> {
+    pub output: &'a mut Vec<u8>,
+    pub remaining: usize,
+}
+
+// Caps on the memory a peer can make us spend on it. A peer that goes past one is disconnected
+// with a protocol violation.
+#[derive(Debug, Clone)]
+pub struct MessageLimits {
+    // Largest message after decompression
+    pub max_message_size: usize,
+    // Streamed messages held back until the ones before them arrive
+    pub max_pending_bytes: usize,
+}
+
+// Primes the compressor with content typical of the application's messages, which small
+// messages can then refer back to. Both sides need the same dictionary, told apart by `id`.
+#[derive(Debug)]
+pub struct CompressionDictionary {
+    pub id: u32,
+    pub bytes: Vec<u8>,
+}
+
 pub struct EncryptionManager {
-    pub key: aead::LessSafeKey,
-    pub nonce_sequence: u64,
+    pub key: Option<aead::LessSafeKey>,
+    pub private_key: Option<agreement::EphemeralPrivateKey>,
+    pub public_key: Vec<u8>,
+    pub initiator: bool,
+    // Keep the derived key in `exported_key`, to be written to a keylog
+    #[cfg(feature = "keylog")]
+    pub export_key: bool,
+    #[cfg(feature = "keylog")]
+    pub exported_key: Option<[u8; 32]>,
 }
 
-pub struct NetworkProtocol {
-    pub socket: UdpSocket,
+// Per-peer protocol state. Never touches a socket or the clock: datagrams and the
+// current time are fed in, and outgoing datagrams queue up in `transmits`.
+pub struct Session {
+    pub peer: SocketAddr,
+    // Chosen by the side that connects; identifies the session whichever address it arrives from
+    pub connection_id: u64,
     pub state: ProtocolState,
     pub buffer: PacketBuffer,
+    // Reliable messages that didn't fit in the outgoing queue, queued in order as it drains
+    pub backlog: VecDeque<u32>,
     pub sequence_number: u32,
     pub ack_number: u32,
+    pub ack_bits: u32,
+    pub ack_pending: bool,
+    // Highest authenticated sequence; only newer packets can move the session to a new address
+    pub largest_received: u32,
+    // Address being validated, the token sent to it and when
+    pub path_challenge: Option<(SocketAddr, u64, Instant)>,
+    // Set while datagrams to the peer go through a relay instead of straight to `peer`
+    pub relay: Option<SocketAddr>,
+    pub message_id: u32,
     pub congestion: CongestionControl,
     pub encryption: EncryptionManager,
-    pub reliable_packets: HashMap<u32, Packet>,
+    pub compression: CompressionConfig,
+    pub negotiated: NegotiatedCompression,
+    // Set up when the handshake completes if streaming was agreed
+    pub stream: Option<CompressionStream>,
+    pub limits: MessageLimits,
+    pub stats: ConnectionStats,
+    // Set once the session has ended, for the core to report
+    pub disconnect_reason: Option<DisconnectReason>,
+    pub pool: Arc<BufferPool>,
+    pub reliable_packets: BTreeMap<u32, Packet>,
+    pub sent_packets: BTreeMap<u32, SentPacket>,
+    pub received_messages: HashSet<u32>,
+    pub events: VecDeque<DeliveryEvent>,
+    pub transmits: VecDeque<(Vec<u8>, SocketAddr)>,
+    pub last_activity: Instant,
+    pub last_send: Instant,
+    pub next_flush: Instant,
+    pub keepalive: Duration,
     pub timeout: Duration,
+    // Entered whenever the session handles input, so its log events carry the peer
+    pub span: tracing::Span,
+}
+
+// Sans-IO core for one local address, demultiplexing datagrams into sessions by peer
+pub struct ProtocolCore {
+    pub sessions: BTreeMap<SocketAddr, Session>,
+    // Which address each connection id's session is currently keyed by
+    pub connection_ids: BTreeMap<u64, SocketAddr>,
+    // Transmitted datagrams come from the pool and should be returned with `BufferPool::put`
+    pub transmits: VecDeque<(Vec<u8>, SocketAddr)>,
+    pub events: VecDeque<ProtocolEvent>,
+    // Delivery events that didn't fit in `events`, counted per peer until there is room to
+    // report them
+    pub unreported: BTreeMap<SocketAddr, u64>,
+    pub pool: Arc<BufferPool>,
+    // Offered by new sessions unless `connect_with` says otherwise
+    pub compression: CompressionConfig,
+    // Applied to new sessions
+    pub limits: MessageLimits,
+    // Whether new sessions export their keys, which then queue up in `exported_keys` along
+    // with their connection ids
+    #[cfg(feature = "keylog")]
+    pub export_keys: bool,
+    #[cfg(feature = "keylog")]
+    pub exported_keys: VecDeque<(u64, [u8; 32])>,
+    // Counters of sessions that have ended
+    pub closed_stats: EndpointStats,
+    pub rendezvous: Option<Rendezvous>,
+    pub punches: BTreeMap<u64, HolePunch>,
+    pub relays: BTreeMap<u64, RelayedPath>,
+    // Set when this core also introduces other peers to each other
+    pub introducer: Option<Introducer>,
+    // Set when this core also relays for other peers
+    pub relay: Option<Relay>,
+}
+
+// Our registration with an introducer, renewed so that the NAT mapping stays open
+pub struct Rendezvous {
+    pub introducer: SocketAddr,
+    pub peer_id: u64,
+    // Addresses peers on the same network can reach us at directly
+    pub local_candidates: Vec<SocketAddr>,
+    pub public_addr: Option<SocketAddr>,
+    pub next_register: Instant,
+}
+
+// An attempt to open a path to another registered peer through both NATs
+pub struct HolePunch {
+    pub peer_id: u64,
+    // Empty until the introducer's introduction arrives
+    pub candidates: Vec<SocketAddr>,
+    pub initiator: bool,
+    // Where to fall back to if no direct path opens
+    pub relay: Option<RelayOffer>,
+    pub next_probe: Instant,
+    pub deadline: Instant,
+}
+
+// Lets two introduced peers use a relay for one connection id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayOffer {
+    pub relay: SocketAddr,
+    pub connection_id: u64,
+    // HMAC of the connection id and side under the secret the relay and introducer share
+    pub credential: [u8; 32],
+}
+
+// A path through a relay, set up when hole punching fails. The session is keyed by
+// `peer`, where the peer seemed to be, though its datagrams go to the relay.
+pub struct RelayedPath {
+    pub peer_id: u64,
+    pub peer: SocketAddr,
+    pub offer: RelayOffer,
+    pub initiator: bool,
+    pub allocated: bool,
+    pub next_allocate: Instant,
+    pub deadline: Instant,
+}
+
+// Forwards datagrams between the two peers of each allocation, matching them by connection
+// id. It never holds session keys, so it can neither read nor forge what it forwards.
+pub struct Relay {
+    pub secret: hmac::Key,
+    pub limits: RelayLimits,
+    pub allocations: BTreeMap<u64, RelayAllocation>,
+    // Bytes the relay as a whole may still forward, refilled at `total_bytes_per_second`
+    pub allowance: f64,
+    pub refilled_at: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayLimits {
+    // Per allocation, with up to `burst` bytes saved up
+    pub bytes_per_second: u64,
+    pub burst: u64,
+    pub total_bytes_per_second: Option<u64>,
+    pub max_allocations: usize,
+    // Allocations that forward nothing for this long are freed
+    pub idle_timeout: Duration,
+}
+
+pub struct RelayAllocation {
+    // Where each side, indexed by `initiator`, last presented its credential from
+    pub peers: [Option<SocketAddr>; 2],
+    pub allowance: f64,
+    pub refilled_at: Instant,
+    pub last_active: Instant,
+    pub forwarded_bytes: u64,
+    pub dropped_bytes: u64,
+}
+
+// Remembers where registered peers can be reached and introduces them on request
+pub struct Introducer {
+    pub peers: BTreeMap<u64, RegisteredPeer>,
+    // Registrations not renewed for this long are forgotten
+    pub expiry: Duration,
+    // Relay offered with every introduction, and the secret its credentials are signed with
+    pub relay: Option<(SocketAddr, hmac::Key)>,
+}
+
+pub struct RegisteredPeer {
+    pub public_addr: SocketAddr,
+    pub local_candidates: Vec<SocketAddr>,
+    pub last_seen: Instant,
+}
+
+// Datagram I/O underneath a `NetworkProtocol`
+pub trait Transport {
+    fn send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize>;
+    // Never blocks: returns `WouldBlock` when nothing is waiting
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    // Blocks until a datagram is waiting or `timeout` passes (forever when None)
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Receives as many waiting datagrams as `bufs` can hold, recording each one's size and
+    // source in `received`. Returns `WouldBlock` when nothing is waiting.
+    fn recv_batch(
+        &self,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        received.clear();
+
+        for buf in bufs.iter_mut() {
+            match self.recv_from(buf) {
+                Ok(datagram) => received.push(datagram),
+                // Whatever went wrong will come up again on the next call
+                Err(_) if !received.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Sends datagrams in order until one fails, returning how many went out
+    fn send_batch(&self, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+        for (sent, (datagram, to)) in datagrams.iter().enumerate() {
+            if let Err(e) = self.send_to(datagram, *to) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+
+        Ok(datagrams.len())
+    }
+}
+
+// Options applied to a UDP socket before it is bound; unset options keep the OS defaults
+#[derive(Debug, Clone, Default)]
+pub struct SocketConfig {
+    // The kernel may cap these (net.core.rmem_max / wmem_max on Linux)
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    // Differentiated services code point, e.g. 46 (expedited forwarding) for real-time traffic
+    pub dscp: Option<u8>,
+    pub reuse_address: bool,
+    // Lets several sockets share a port, with the kernel spreading datagrams between them
+    pub reuse_port: bool,
+    // Oversized datagrams fail to send instead of being fragmented
+    pub dont_fragment: bool,
+    // Stops IPv6 sockets from also serving IPv4 peers
+    pub only_v6: bool,
+}
+
+// UDP socket that coalesces datagrams with segmentation offload (UDP GSO/GRO on Linux) when
+// the kernel supports it, so one syscall carries many datagrams. Elsewhere it is a plain socket.
+pub struct OffloadSocket {
+    pub socket: UdpSocket,
+    pub socket_is_ipv6: bool,
+    // Cleared for good if the kernel rejects a segmented send
+    pub gso: AtomicBool,
+    pub gro: bool,
+    // Segments of a coalesced read that the caller hasn't taken yet
+    pub pending: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    pub recv_buffer: Mutex<Vec<u8>>,
+    pub send_buffer: Mutex<Vec<u8>>,
+}
+
+// Source of time for the protocol driver, so that tests can control it
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+// Only moves when advanced; clones share the same time
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    pub now: Arc<Mutex<Instant>>,
+}
+
+// Drives a `ProtocolCore` over a transport, a UDP socket on the system clock unless stated otherwise
+pub struct NetworkProtocol<T: Transport = UdpSocket, C: Clock = SystemClock> {
+    pub transport: T,
+    pub core: ProtocolCore,
+    pub clock: C,
+    // Reused between calls so batched reads and writes don't allocate
+    pub recv_buffers: Vec<Vec<u8>>,
+    pub received: Vec<(usize, SocketAddr)>,
+    pub send_queue: Vec<(Vec<u8>, SocketAddr)>,
+    pub capture: Option<PacketCapture>,
+    // Session keys as `<connection id> <key>` lines in hex, for decrypting captures
+    #[cfg(feature = "keylog")]
+    pub keylog: Option<BufWriter<File>>,
+}
+
+// Writes the datagrams an endpoint sends and receives to a pcap file, wrapped in made-up IP
+// and UDP headers so that standard tools can open it
+pub struct PacketCapture {
+    pub pcap: BufWriter<File>,
+    // Our side of every captured datagram
+    pub local_addr: SocketAddr,
+}
+
+// A UDP datagram read back from a capture
+#[derive(Debug, Clone)]
+pub struct CapturedDatagram {
+    // Since the Unix epoch
+    pub timestamp: Duration,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+// Follows connections through captured traffic, decrypting with keys from a keylog
+pub struct Inspector {
+    pub keys: HashMap<u64, [u8; 32]>,
+    // Accepts any codec a peer chose; holds the dictionary, if one was given
+    pub compression: CompressionConfig,
+    pub limits: MessageLimits,
+    pub connections: BTreeMap<u64, InspectedConnection>,
+}
+
+// What the inspector has pieced together about one connection
+pub struct InspectedConnection {
+    // Where the Connect came from, once one has been seen
+    pub initiator: Option<SocketAddr>,
+    pub state: ProtocolState,
+    // Opens datagrams sent by the acceptor and by the initiator, in that order
+    pub openers: Option<[EncryptionManager; 2]>,
+    pub negotiated: NegotiatedCompression,
+    // Each direction's reliable stream, indexed the same way
+    pub streams: [Option<CompressionStream>; 2],
+    // Reliable message ids seen in each direction, to spot retransmissions
+    pub messages: [HashSet<u32>; 2],
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    pub packets: u64,
+    pub bytes: u64,
+    pub retransmissions: u64,
+    pub decrypt_failures: u64,
+}
+
+// One captured datagram, as far as the inspector could read it
+#[derive(Debug)]
+pub struct InspectedPacket {
+    pub datagram: CapturedDatagram,
+    // The header, with the decrypted body as its data
+    pub packet: Option<Packet>,
+    pub rendezvous: Option<RendezvousMessage>,
+    // Whether the initiator sent it, once that is known
+    pub from_initiator: Option<bool>,
+    // A handshake or reliable message seen before in the same direction
+    pub retransmission: bool,
+    // The state the packet moved its connection to
+    pub transition: Option<ProtocolState>,
+    // Messages the packet delivered. A streamed one may be held back and then delivered along
+    // with a later one.
+    pub messages: Vec<Vec<u8>>,
+    // Why the body couldn't be read
+    pub error: Option<String>,
+}
+
+// A datagram in flight between memory transports, with its source address
+pub type MemoryDatagram = (Vec<u8>, SocketAddr);
+
+// Routes datagrams between in-process transports by address, without binding ports
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    pub endpoints: Arc<Mutex<HashMap<SocketAddr, Sender<MemoryDatagram>>>>,
+}
+
+pub struct MemoryTransport {
+    pub local_addr: SocketAddr,
+    pub network: MemoryNetwork,
+    pub receiver: Receiver<MemoryDatagram>,
+    // Datagram taken off the channel by `wait` but not yet read
+    pub pending: Mutex<Option<MemoryDatagram>>,
+}
+
+// Link impairments applied to outgoing datagrams; the default is a perfect link
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    pub enabled: bool,
+    pub latency: Duration,
+    pub jitter: Duration,
+    // Gilbert-Elliott loss: `loss` applies in the good state, `burst_loss` in the bad one
+    pub loss: f64,
+    pub burst_loss: f64,
+    pub burst_enter: f64,
+    pub burst_exit: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    // Bytes per second, None for unlimited
+    pub bandwidth: Option<u64>,
+}
+
+pub struct DelayedDatagram {
+    pub release_at: Instant,
+    // Breaks ties so datagrams released together keep their send order
+    pub order: u64,
+    pub datagram: Vec<u8>,
+    pub to: SocketAddr,
+}
+
+pub struct ConditionerState {
+    pub rng: StdRng,
+    pub bursting: bool,
+    pub link_free_at: Instant,
+    pub next_order: u64,
+    pub queue: BinaryHeap<Reverse<DelayedDatagram>>,
+}
+
+pub struct SimulatedNode {
+    pub core: ProtocolCore,
+    // Applied to everything this node sends
+    pub conditions: NetworkConditions,
+    pub link: ConditionerState,
+    // Public IP of the NAT this node sits behind, if any
+    pub nat: Option<IpAddr>,
+}
+
+// Port-restricted cone NAT, unless symmetric: each private address keeps one public port
+// whoever it sends to, and only addresses it has sent to can send back. No hairpinning.
+pub struct SimulatedNat {
+    // A symmetric NAT maps each destination to a new public port, which defeats hole punching
+    pub symmetric: bool,
+    pub next_port: u16,
+    // (private address, destination for a symmetric NAT) to public address
+    pub mappings: BTreeMap<(SocketAddr, Option<SocketAddr>), SocketAddr>,
+    // (private address, remote address) pairs allowed in
+    pub permissions: BTreeSet<(SocketAddr, SocketAddr)>,
+}
+
+// Steps protocol cores over a simulated network on a virtual clock. The same seed and the
+// same calls give every link the same losses, delays and reorderings, so a run's timing and
+// outcome repeat. The bytes don't: connection ids, tokens and keys still come from the OS.
+pub struct Simulation {
+    pub clock: VirtualClock,
+    pub rng: StdRng,
+    pub nodes: BTreeMap<SocketAddr, SimulatedNode>,
+    pub nats: BTreeMap<IpAddr, SimulatedNat>,
+}
+
+// Wraps a transport to simulate a bad network. Delayed datagrams are handed to the
+// inner transport whenever it is used, so the owner has to keep polling it.
+pub struct ConditionedTransport<T: Transport> {
+    pub inner: T,
+    pub conditions: Arc<Mutex<NetworkConditions>>,
+    pub state: Mutex<ConditionerState>,
+}
+
+// Async handle to a `NetworkProtocol` driven by a background tokio task
+#[cfg(feature = "tokio")]
+pub struct Endpoint {
+    pub local_addr: SocketAddr,
+    pub commands: mpsc::UnboundedSender<EndpointCommand>,
+    pub accepted: mpsc::UnboundedReceiver<Connection>,
+}
+
+#[cfg(feature = "tokio")]
+pub struct Connection {
+    pub peer: SocketAddr,
+    pub commands: mpsc::UnboundedSender<EndpointCommand>,
+    pub incoming: mpsc::Receiver<Payload>,
+    pub events: mpsc::Receiver<DeliveryEvent>,
+}
+
+// Observations counted by the smallest bucket bound they fit under
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub bounds: Vec<f64>,
+    // One per bound, then one for whatever is above the last
+    pub counts: Vec<u64>,
+    pub sum: f64,
+}
+
+// What the `/metrics` endpoint reports. The server loop keeps it current with `record`.
+#[cfg(feature = "metrics")]
+pub struct Metrics {
+    pub endpoint: EndpointStats,
+    // Connecting and connected sessions
+    pub states: [usize; 2],
+    // Every connection's smoothed RTT, sampled periodically
+    pub rtt: Histogram,
+    pub next_sample: Instant,
+    // Only reported by servers that tick
+    pub tick_duration: Option<Histogram>,
+    pub players: Option<usize>,
 }
@@ -1,3 +1,17 @@
 pub mod def;
 
-pub use def::{CongestionControl, EncryptionManager, NetworkProtocol, Packet, PacketBuffer};
+pub use def::{
+    BufferPool, CapturedDatagram, Clock, CompressionConfig, CompressionDictionary,
+    CompressionStream, ConditionedTransport, ConditionerState, CongestionControl, ConnectionStats,
+    DelayedDatagram, EncryptionManager, EndpointStats, HolePunch, InspectedConnection,
+    InspectedPacket, Inspector, Introducer, LimitedWriter, MemoryNetwork, MemoryTransport,
+    MessageLimits, NegotiatedCompression, NetworkConditions, NetworkProtocol, OffloadSocket,
+    Packet, PacketBuffer, PacketCapture, PooledBuffer, ProtocolCore, RegisteredPeer, Relay,
+    RelayAllocation, RelayLimits, RelayOffer, RelayedPath, Rendezvous, SentPacket, Session,
+    SimulatedNat, SimulatedNode, Simulation, SocketConfig, SystemClock, Transport, VirtualClock,
+};
+
+#[cfg(feature = "tokio")]
+pub use def::{Connection, Endpoint};
+#[cfg(feature = "metrics")]
+pub use def::{Histogram, Metrics};
@@ -0,0 +1,24 @@
+// How message payloads are compressed. Levels only matter to the sender: the receiver needs
+// the algorithm, not how hard the other side tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    // 0 (fastest) to 9 (smallest)
+    Zlib(u32),
+    Lz4,
+    // 1 to 22, or negative for faster than level 1
+    Zstd(i32),
+}
+
+impl Codec {
+    // Identifies the algorithm during the handshake
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zlib(_) => 1,
+            Self::Lz4 => 2,
+            Self::Zstd(_) => 3,
+        }
+    }
+}
@@ -0,0 +1,27 @@
+// Why a session ended, as reported with `ProtocolEvent::Disconnected`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // The handshake never completed, or the peer went quiet
+    TimedOut,
+    // A reliable message ran out of retransmissions
+    Unreachable,
+    // Another connection proved it now owns the peer's address
+    Replaced,
+    // Closed by the application
+    Closed,
+    // Every sequence, and so every nonce, was used under the session key; reconnecting
+    // derives a new one
+    KeyExhausted,
+    ProtocolViolation(Violation),
+}
+
+// Ways a peer can break the protocol, each of which ends its connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    // Decompressed, or would have decompressed, past `MessageLimits::max_message_size`
+    MessageTooLarge,
+    // Held more out-of-order messages than `MessageLimits::max_pending_bytes`
+    ReassemblyOverflow,
+    // Failed to decompress or deserialize
+    MalformedMessage,
+}
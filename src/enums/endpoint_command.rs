@@ -0,0 +1,27 @@
+use std::{io, net::SocketAddr};
+
+use tokio::sync::oneshot;
+
+use crate::definitions::{Connection, ConnectionStats, EndpointStats};
+
+// Requests from `Endpoint`/`Connection` handles to the task driving the protocol
+pub enum EndpointCommand {
+    Connect {
+        // Every resolved address; the driver picks one its socket can reach
+        remote_addrs: Vec<SocketAddr>,
+        reply: oneshot::Sender<io::Result<Connection>>,
+    },
+    Send {
+        peer: SocketAddr,
+        data: Vec<u8>,
+        reliable: bool,
+        reply: oneshot::Sender<io::Result<u32>>,
+    },
+    Stats {
+        peer: SocketAddr,
+        reply: oneshot::Sender<Option<ConnectionStats>>,
+    },
+    EndpointStats {
+        reply: oneshot::Sender<EndpointStats>,
+    },
+}
@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+
+use crate::enums::DisconnectReason;
+
+// Reported once per message id, for reliable and unreliable sends alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryEvent {
+    Delivered(u32),
+    Lost(u32),
+    // Receipts for this many messages were discarded because events weren't polled in time,
+    // so whether those messages arrived is unknown
+    Unreported(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolEvent {
+    Connected(SocketAddr),
+    Disconnected(SocketAddr, DisconnectReason),
+    Delivery(SocketAddr, DeliveryEvent),
+    // The peer proved it moved to a new address (NAT rebinding, network change), which the
+    // connection is now known by. Carries the old and new addresses.
+    Migrated(SocketAddr, SocketAddr),
+    // The introducer has told us our public address
+    Registered(SocketAddr),
+    // A path to the peer with this id works; the initiating side connects over it
+    PunchSucceeded(u64, SocketAddr),
+    // No direct path opened, so the peer is reached through a relay, still by this address
+    Relayed(u64, SocketAddr),
+    PunchFailed(u64),
+}
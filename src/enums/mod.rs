@@ -1,3 +1,19 @@
+mod codec;
+mod disconnect;
+#[cfg(feature = "tokio")]
+mod endpoint_command;
+mod events;
+mod packets;
+mod payload;
 mod protocols;
+mod rendezvous;
 
+pub use codec::Codec;
+pub use disconnect::{DisconnectReason, Violation};
+#[cfg(feature = "tokio")]
+pub use endpoint_command::EndpointCommand;
+pub use events::{DeliveryEvent, ProtocolEvent};
+pub use packets::PacketKind;
+pub use payload::Payload;
 pub use protocols::ProtocolState;
+pub use rendezvous::RendezvousMessage;
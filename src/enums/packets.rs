@@ -0,0 +1,26 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    Connect = 0x01,
+    Accept = 0x02,
+    Reliable = 0x03,
+    Unreliable = 0x04,
+    Ack = 0x05,
+    // Probe a new peer address before a connection migrates to it
+    PathChallenge = 0x06,
+    PathResponse = 0x07,
+}
+
+impl PacketKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::Connect),
+            0x02 => Some(Self::Accept),
+            0x03 => Some(Self::Reliable),
+            0x04 => Some(Self::Unreliable),
+            0x05 => Some(Self::Ack),
+            0x06 => Some(Self::PathChallenge),
+            0x07 => Some(Self::PathResponse),
+            _ => None,
+        }
+    }
+}
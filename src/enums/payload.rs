@@ -0,0 +1,63 @@
+use std::ops::Deref;
+
+use bytes::Bytes;
+
+use crate::definitions::PooledBuffer;
+
+// Message bytes. Outgoing messages are shared with the retransmission queue, so resending
+// one doesn't copy it; received ones stay in a pooled buffer until the application drops them.
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Shared(Bytes),
+    Pooled(PooledBuffer),
+}
+
+impl Payload {
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Self::Shared(bytes) => bytes.into(),
+            Self::Pooled(buffer) => buffer.into_vec(),
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Shared(bytes) => bytes,
+            Self::Pooled(buffer) => buffer,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::Shared(Bytes::new())
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Shared(data.into())
+    }
+}
+
+impl From<Bytes> for Payload {
+    fn from(bytes: Bytes) -> Self {
+        Self::Shared(bytes)
+    }
+}
+
+impl From<PooledBuffer> for Payload {
+    fn from(buffer: PooledBuffer) -> Self {
+        Self::Pooled(buffer)
+    }
+}
@@ -0,0 +1,53 @@
+use std::net::SocketAddr;
+
+use crate::definitions::RelayOffer;
+
+// Messages between peers and an introducer or relay, and the probes peers punch through
+// their NATs with. They travel unencrypted on the protocol's own socket, so a peer's public
+// address is the one its sessions will use. Kinds start at 0x10, clear of `PacketKind`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RendezvousMessage {
+    Register {
+        peer_id: u64,
+        local_candidates: Vec<SocketAddr>,
+    },
+    // The address the introducer saw the registration come from
+    Registered {
+        public_addr: SocketAddr,
+    },
+    Introduce {
+        peer_id: u64,
+        target: u64,
+    },
+    // Sent to both peers; the initiator opens the session once a path works
+    Introduction {
+        peer_id: u64,
+        candidates: Vec<SocketAddr>,
+        initiator: bool,
+        relay: Option<RelayOffer>,
+    },
+    UnknownPeer {
+        peer_id: u64,
+    },
+    Punch {
+        from: u64,
+        to: u64,
+    },
+    PunchAck {
+        from: u64,
+        to: u64,
+    },
+    // Claims one side of a relay allocation
+    Allocate {
+        connection_id: u64,
+        initiator: bool,
+        credential: [u8; 32],
+    },
+    Allocated {
+        connection_id: u64,
+    },
+    // Bad credential, or the relay is full
+    Refused {
+        connection_id: u64,
+    },
+}
@@ -0,0 +1,44 @@
+use std::{env, process};
+
+use dserve::game_server::replay::{read_recording, Replay};
+
+// Replays a client session recorded with DSERVE_RECORD set against a fresh game server, and
+// reports every state update that came out differently. Speed 1 keeps the recorded timing,
+// higher speeds run faster, and 0 runs as fast as possible.
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: game_replay <recording> [speed]");
+        process::exit(2);
+    }
+
+    let speed = match args.get(2) {
+        Some(speed) => speed
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        None => 1.0,
+    };
+
+    let recording = read_recording(&args[1])?;
+    println!("Replaying {} messages at {}x", recording.len(), speed);
+
+    let report = Replay::new(&recording)?.run(speed)?;
+    for mismatch in &report.mismatches {
+        println!("game time {}:", mismatch.recorded.game_time);
+        println!("  recorded {:?}", mismatch.recorded);
+        match &mismatch.replayed {
+            Some(replayed) => println!("  replayed {:?}", replayed),
+            None => println!("  replayed nothing"),
+        }
+    }
+
+    println!(
+        "{} of {} snapshots matched",
+        report.compared - report.mismatches.len(),
+        report.compared
+    );
+    if !report.mismatches.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -1,45 +1,87 @@
-use std::{collections::VecDeque, time::Duration};
-
-use crate::definitions::NetworkProtocol;
-
-use super::types::{GameMessage, GameState, PlayerState, Vector2};
-
-pub struct GameClient {
-    pub protocol: NetworkProtocol,
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::Path,
+};
+
+use crate::{
+    definitions::{NetworkProtocol, Transport},
+    enums::{DisconnectReason, Violation},
+};
+
+use super::{
+    replay::Recorder,
+    types::{Direction, GameMessage, GameState, PlayerState, Vector2},
+};
+
+pub struct GameClient<T: Transport = UdpSocket> {
+    pub protocol: NetworkProtocol<T>,
+    pub server: Option<SocketAddr>,
     pub state: Option<GameState>,
     pub player_id: Option<u32>,
     pub interpolation_buffer: VecDeque<GameState>,
+    // Every message sent and received is written here, for replaying against a server
+    pub recorder: Option<Recorder>,
 }
 
 impl GameClient {
-    pub fn new(addr: &str) -> std::io::Result<Self> {
-        Ok(Self {
-            protocol: NetworkProtocol::new(addr)?,
+    pub fn new<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        Ok(Self::with_protocol(NetworkProtocol::new(addr)?))
+    }
+}
+
+impl<T: Transport> GameClient<T> {
+    pub fn with_transport(transport: T) -> Self {
+        Self::with_protocol(NetworkProtocol::with_transport(transport))
+    }
+
+    fn with_protocol(protocol: NetworkProtocol<T>) -> Self {
+        Self {
+            protocol,
+            server: None,
             state: None,
             player_id: None,
             interpolation_buffer: VecDeque::with_capacity(128),
-        })
+            recorder: None,
+        }
     }
 
-    pub fn connect(&mut self, server_addr: &str) -> std::io::Result<()> {
-        self.protocol.connect(server_addr)?;
+    // Call before connecting, so the recording includes joining
+    pub fn record_to<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.recorder = Some(Recorder::create(path)?);
+        Ok(())
+    }
+
+    pub fn connect<A: ToSocketAddrs>(&mut self, server_addr: A) -> std::io::Result<()> {
+        let server = self.protocol.connect(server_addr)?;
+        self.server = Some(server);
 
         // Send join request
         let join_message = GameMessage::PlayerJoin(0);
         let serialized =
             bincode::serialize(&join_message).expect("Failed to serialize join message");
-        self.protocol.send_reliable(serialized)?;
+        self.protocol.send_reliable(server, serialized)?;
+        self.record(Direction::Sent, &join_message)?;
 
         Ok(())
     }
 
     pub fn update(&mut self) -> std::io::Result<()> {
         self.protocol.update()?;
+        self.tick()
+    }
 
+    pub fn tick(&mut self) -> std::io::Result<()> {
         // Process incoming messages
-        while let Some(packet) = self.protocol.buffer.incoming.pop_front() {
-            let message: GameMessage =
-                bincode::deserialize(&packet.data).expect("Failed to deserialize game message");
+        while let Some((peer, packet)) = self.protocol.poll_message() {
+            let Ok(message) = GameMessage::decode(&packet.data) else {
+                self.protocol.disconnect(
+                    peer,
+                    DisconnectReason::ProtocolViolation(Violation::MalformedMessage),
+                );
+                continue;
+            };
+            self.record(Direction::Received, &message)?;
 
             match message {
                 GameMessage::StateUpdate(new_state) => {
@@ -48,6 +90,9 @@ impl GameClient {
                         self.interpolation_buffer.pop_front();
                     }
                 }
+                GameMessage::PlayerIdAssigned(player_id) => {
+                    self.player_id = Some(player_id);
+                }
                 _ => {}
             }
         }
@@ -59,7 +104,7 @@ impl GameClient {
     }
 
     pub fn send_input(&mut self, movement: Vector2) -> std::io::Result<()> {
-        if let Some(player_id) = self.player_id {
+        if let (Some(player_id), Some(server)) = (self.player_id, self.server) {
             let input = GameMessage::PlayerInput {
                 player_id,
                 movement,
@@ -70,11 +115,19 @@ impl GameClient {
             };
 
             let serialized = bincode::serialize(&input).expect("Failed to serialize input");
-            self.protocol.send_reliable(serialized)?;
+            self.protocol.send_reliable(server, serialized)?;
+            self.record(Direction::Sent, &input)?;
         }
         Ok(())
     }
 
+    fn record(&mut self, direction: Direction, message: &GameMessage) -> std::io::Result<()> {
+        match self.recorder.as_mut() {
+            Some(recorder) => recorder.record(direction, message),
+            None => Ok(()),
+        }
+    }
+
     pub fn interpolate_state(&mut self) {
         if self.interpolation_buffer.len() < 2 {
             return;
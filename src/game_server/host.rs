@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, ErrorKind, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::Path,
+};
+
+use tracing::{info, trace, warn};
+
+use crate::{
+    definitions::{NetworkProtocol, Transport},
+    enums::{DisconnectReason, ProtocolEvent, ProtocolState, Violation},
+    implementations::write_sample,
+};
+
+use super::types::{GameMessage, GameState, PlayerState, Vector2};
+
+pub struct GameServer<T: Transport = UdpSocket> {
+    pub protocol: NetworkProtocol<T>,
+    pub state: GameState,
+    pub clients: HashMap<u32, SocketAddr>,
+    pub next_player_id: u32,
+    // Serialized state updates are written here, for training a compression dictionary
+    pub capture: Option<BufWriter<File>>,
+}
+
+impl GameServer {
+    pub fn new<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        Ok(Self::with_protocol(NetworkProtocol::new(addr)?))
+    }
+}
+
+impl<T: Transport> GameServer<T> {
+    pub fn with_transport(transport: T) -> Self {
+        Self::with_protocol(NetworkProtocol::with_transport(transport))
+    }
+
+    fn with_protocol(protocol: NetworkProtocol<T>) -> Self {
+        Self {
+            protocol,
+            state: GameState {
+                players: HashMap::new(),
+                game_time: 0,
+            },
+            clients: HashMap::new(),
+            next_player_id: 1,
+            capture: None,
+        }
+    }
+
+    pub fn capture_to<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.capture = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    pub fn update(&mut self) -> std::io::Result<()> {
+        // Update network
+        self.protocol.update()?;
+        self.tick()
+    }
+
+    // One simulation step over whatever the network has delivered since the last one
+    pub fn tick(&mut self) -> std::io::Result<()> {
+        // Drop players whose connection has ended and follow those who changed address
+        while let Some(event) = self.protocol.poll_event() {
+            match event {
+                ProtocolEvent::Disconnected(peer, _) => self.remove_client(peer),
+                ProtocolEvent::Migrated(from, to) => {
+                    for client in self.clients.values_mut().filter(|client| **client == from) {
+                        *client = to;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Process incoming messages
+        while let Some((peer, packet)) = self.protocol.poll_message() {
+            trace!(%peer, ?packet, "received");
+            // Messages already queued from a peer disconnected earlier in this loop
+            if self.protocol.state(peer) != ProtocolState::Connected {
+                continue;
+            }
+            let Ok(message) = GameMessage::decode(&packet.data) else {
+                self.protocol.disconnect(
+                    peer,
+                    DisconnectReason::ProtocolViolation(Violation::MalformedMessage),
+                );
+                // The session is gone at once, so no Disconnected event will come for it
+                self.remove_client(peer);
+                continue;
+            };
+
+            match message {
+                GameMessage::PlayerInput {
+                    player_id,
+                    movement,
+                    timestamp,
+                } => {
+                    if let Some(player) = self.state.players.get_mut(&player_id) {
+                        player.velocity = movement;
+                        player.last_update = timestamp;
+                    }
+                }
+                GameMessage::PlayerJoin(_) => {
+                    let player_id = self.next_player_id;
+                    self.next_player_id += 1;
+                    info!(%peer, player_id, "player joined");
+
+                    let new_player = PlayerState {
+                        position: Vector2 { x: 0.0, y: 0.0 },
+                        velocity: Vector2 { x: 0.0, y: 0.0 },
+                        health: 100,
+                        last_update: self.state.game_time,
+                    };
+
+                    self.state.players.insert(player_id, new_player);
+                    self.clients.insert(player_id, peer);
+
+                    let id_message = GameMessage::PlayerIdAssigned(player_id);
+                    let serialized =
+                        bincode::serialize(&id_message).expect("Failed to serialize player ID");
+                    self.protocol.send_reliable(peer, serialized)?;
+                }
+                GameMessage::PlayerLeave(player_id) => {
+                    self.state.players.remove(&player_id);
+                }
+                _ => {}
+            }
+        }
+
+        // Update game state
+        self.update_game_state();
+
+        // Broadcast state to all clients
+        let state_update = GameMessage::StateUpdate(self.state.clone());
+        let serialized = bincode::serialize(&state_update).expect("Failed to serialize game state");
+
+        if let Some(capture) = self.capture.as_mut() {
+            write_sample(capture, &serialized)?;
+            capture.flush()?;
+        }
+
+        // A client whose session ended since it was added only misses this update
+        let mut lost = Vec::new();
+        for client in self.clients.values() {
+            match self.protocol.send_reliable(*client, serialized.clone()) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotConnected => lost.push(*client),
+                Err(e) => return Err(e),
+            }
+        }
+        for client in lost {
+            warn!(peer = %client, "dropping player without a session");
+            self.remove_client(client);
+        }
+
+        Ok(())
+    }
+
+    fn remove_client(&mut self, peer: SocketAddr) {
+        self.clients.retain(|player_id, client| {
+            let connected = *client != peer;
+            if !connected {
+                self.state.players.remove(player_id);
+            }
+            connected
+        });
+    }
+
+    pub fn update_game_state(&mut self) {
+        self.state.game_time += 1;
+
+        // Update player positions based on velocity
+        for player in self.state.players.values_mut() {
+            player.position.x += player.velocity.x;
+            player.position.y += player.velocity.y;
+        }
+    }
+}
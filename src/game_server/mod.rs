@@ -1,2 +1,4 @@
 pub mod client;
+pub mod host;
+pub mod replay;
 pub mod types;
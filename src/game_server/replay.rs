@@ -0,0 +1,444 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    mem,
+    net::SocketAddr,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    definitions::{MemoryNetwork, MemoryTransport, NetworkProtocol},
+    enums::ProtocolState,
+    implementations::{read_samples, write_sample},
+};
+
+use super::{
+    host::GameServer,
+    types::{Direction, GameMessage, GameState, RecordedMessage},
+};
+
+// Long enough for any handshake over the memory network
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+// How long to wait after the last tick for the snapshots still on their way
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Writes every message a client sends and receives, to be replayed against a server later
+pub struct Recorder {
+    pub file: BufWriter<File>,
+    pub started: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, message: &GameMessage) -> io::Result<()> {
+        let recorded = RecordedMessage {
+            elapsed: self.started.elapsed(),
+            direction,
+            message: message.clone(),
+        };
+
+        let serialized = bincode::serialize(&recorded).expect("Failed to serialize recording");
+        write_sample(&mut self.file, &serialized)?;
+        self.file.flush()
+    }
+}
+
+// Recordings use the same framing as compression dictionary captures
+pub fn read_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedMessage>> {
+    read_samples(&mut BufReader::new(File::open(path)?))?
+        .iter()
+        .map(|sample| {
+            bincode::deserialize(sample).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum ReplayEvent {
+    Send(GameMessage),
+    Tick,
+}
+
+// A recorded snapshot the replayed server didn't reproduce
+#[derive(Debug)]
+pub struct SnapshotMismatch {
+    pub recorded: GameState,
+    // None if the server never sent a snapshot for that tick
+    pub replayed: Option<GameState>,
+}
+
+#[derive(Debug)]
+pub struct ReplayReport {
+    pub compared: usize,
+    pub mismatches: Vec<SnapshotMismatch>,
+}
+
+// Plays a recorded client session against a fresh server over a memory network. The server
+// ticks once per recorded snapshot, and each message the client sent reaches it just before
+// the tick that would have taken it in, so the outcome doesn't depend on how fast the replay
+// runs. Other clients' input isn't in the recording, so record sessions that had the server
+// to themselves.
+pub struct Replay {
+    pub server: GameServer<MemoryTransport>,
+    pub client: NetworkProtocol<MemoryTransport>,
+    pub server_addr: SocketAddr,
+    // On the recording's clock
+    pub schedule: Vec<(Duration, ReplayEvent)>,
+    // Ordered by game time
+    pub snapshots: Vec<GameState>,
+}
+
+impl Replay {
+    pub fn new(recording: &[RecordedMessage]) -> io::Result<Self> {
+        let mut received: Vec<(Duration, GameState)> = recording
+            .iter()
+            .filter(|recorded| recorded.direction == Direction::Received)
+            .filter_map(|recorded| match &recorded.message {
+                GameMessage::StateUpdate(state) => Some((recorded.elapsed, state.clone())),
+                _ => None,
+            })
+            .collect();
+        received.sort_by_key(|(_, state)| state.game_time);
+
+        let Some(first) = received.first().map(|(_, state)| state.game_time) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "recording has no state updates",
+            ));
+        };
+
+        let network = MemoryNetwork::new();
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 8000));
+        let mut server = GameServer::with_transport(network.bind(server_addr)?);
+        let client =
+            NetworkProtocol::with_transport(network.bind(SocketAddr::from(([127, 0, 0, 1], 0)))?);
+
+        // Pick up where the recorded server was when the client joined
+        server.state.game_time = first.saturating_sub(1);
+        if let Some(player_id) = recording
+            .iter()
+            .find_map(|recorded| match recorded.message {
+                GameMessage::PlayerIdAssigned(player_id) => Some(player_id),
+                _ => None,
+            })
+        {
+            server.next_player_id = player_id;
+        }
+
+        Ok(Self {
+            server,
+            client,
+            server_addr,
+            schedule: schedule(recording, &received),
+            snapshots: received.into_iter().map(|(_, state)| state).collect(),
+        })
+    }
+
+    // Runs the schedule at `speed` times the recorded pace, or as fast as possible at zero,
+    // then compares the snapshots the server sent with the recorded ones
+    pub fn run(&mut self, speed: f64) -> io::Result<ReplayReport> {
+        self.connect()?;
+
+        let mut replayed = HashMap::new();
+        let started = Instant::now();
+        for (at, event) in mem::take(&mut self.schedule) {
+            if speed > 0.0 {
+                let due = started + at.div_f64(speed);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+
+            match event {
+                ReplayEvent::Send(message) => {
+                    let serialized =
+                        bincode::serialize(&message).expect("Failed to serialize message");
+                    self.client.send_reliable(self.server_addr, serialized)?;
+                }
+                ReplayEvent::Tick => {
+                    self.flush_client()?;
+                    self.server.update()?;
+                    // Sends the snapshot now rather than at the start of the next tick
+                    self.server.protocol.handle_timeout()?;
+                    self.receive(&mut replayed)?;
+                }
+            }
+        }
+
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        while replayed.len() < self.snapshots.len() && Instant::now() < deadline {
+            self.server.protocol.update()?;
+            self.receive(&mut replayed)?;
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mismatches = self
+            .snapshots
+            .iter()
+            .filter_map(|recorded| {
+                let replayed = replayed.remove(&recorded.game_time);
+                (replayed.as_ref() != Some(recorded)).then(|| SnapshotMismatch {
+                    recorded: recorded.clone(),
+                    replayed,
+                })
+            })
+            .collect();
+
+        Ok(ReplayReport {
+            compared: self.snapshots.len(),
+            mismatches,
+        })
+    }
+
+    // Handshakes without ticking the server, which would move the game on
+    fn connect(&mut self) -> io::Result<()> {
+        self.server_addr = self.client.connect(self.server_addr)?;
+
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        while self.client.state(self.server_addr) != ProtocolState::Connected {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server didn't accept the replayed client",
+                ));
+            }
+
+            self.server.protocol.update()?;
+            self.client.update()?;
+        }
+        Ok(())
+    }
+
+    // A single flush sends no more than the congestion window, so this keeps at it until
+    // everything queued for the tick is on its way
+    fn flush_client(&mut self) -> io::Result<()> {
+        loop {
+            self.client.handle_timeout()?;
+
+            let queued = self
+                .client
+                .stats(self.server_addr)
+                .map_or(0, |stats| stats.outgoing_queue);
+            if queued == 0 || self.client.state(self.server_addr) != ProtocolState::Connected {
+                return Ok(());
+            }
+        }
+    }
+
+    fn receive(&mut self, replayed: &mut HashMap<u64, GameState>) -> io::Result<()> {
+        self.client.update()?;
+
+        while let Some((_, packet)) = self.client.poll_message() {
+            if let Ok(GameMessage::StateUpdate(state)) = GameMessage::decode(&packet.data) {
+                replayed.insert(state.game_time, state);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Places each tick when the client received its snapshot, and each message the client sent
+// just before the tick that took it in
+fn schedule(
+    recording: &[RecordedMessage],
+    received: &[(Duration, GameState)],
+) -> Vec<(Duration, ReplayEvent)> {
+    // A snapshot that arrived late was delayed, so its tick came no later than the next one's
+    let mut ticks: Vec<(Duration, &GameState)> = received
+        .iter()
+        .map(|(elapsed, state)| (*elapsed, state))
+        .collect();
+    for i in (1..ticks.len()).rev() {
+        ticks[i - 1].0 = ticks[i - 1].0.min(ticks[i].0);
+    }
+
+    let sent: Vec<&RecordedMessage> = recording
+        .iter()
+        .filter(|recorded| recorded.direction == Direction::Sent)
+        .collect();
+    let unique = unique_timestamps(&sent);
+
+    // Where the recording shows which tick took a message in, that's the one
+    let applied: Vec<Option<usize>> = sent
+        .iter()
+        .map(|recorded| applied_at(recorded, &ticks, &unique))
+        .collect();
+
+    // Otherwise it's the first the message could have made going by the shortest lag seen, but
+    // not before the tick after an input sent earlier, which was the last that tick took in
+    let lag = sent
+        .iter()
+        .zip(&applied)
+        .filter_map(|(recorded, tick)| Some(ticks[(*tick)?].0.saturating_sub(recorded.elapsed)))
+        .min()
+        .unwrap_or_default();
+    let mut floor = 0;
+    let mut targets = Vec::with_capacity(sent.len());
+    for (recorded, applied) in sent.iter().zip(&applied) {
+        let target = match applied {
+            Some(tick) => {
+                if let GameMessage::PlayerInput { .. } = recorded.message {
+                    floor = floor.max(tick + 1);
+                }
+                Some(*tick)
+            }
+            None => ticks
+                .iter()
+                .position(|(tick_at, _)| recorded.elapsed + lag <= *tick_at)
+                .map(|tick| tick.max(floor)),
+        };
+        targets.push(target);
+    }
+
+    // Short of a loss, messages arrive in the order they were sent, so none made a later tick
+    // than anything sent after it. An input that never shows was overtaken in its tick by a
+    // later one that does, or else missed every recorded tick.
+    let mut bound: Option<usize> = None;
+    let mut overtaken_at: Option<usize> = None;
+    for ((target, applied), recorded) in targets.iter_mut().zip(&applied).zip(&sent).rev() {
+        let input = matches!(recorded.message, GameMessage::PlayerInput { .. });
+        match applied {
+            Some(tick) if input => {
+                overtaken_at = Some(overtaken_at.map_or(*tick, |at| at.min(*tick)))
+            }
+            Some(_) => {}
+            None if input => *target = overtaken_at,
+            None => {
+                *target = target
+                    .zip(bound)
+                    .map_or(*target, |(target, bound)| Some(target.min(bound)))
+            }
+        }
+        if let Some(target) = *target {
+            bound = Some(bound.map_or(target, |bound| bound.min(target)));
+        }
+    }
+
+    let mut schedule = Vec::new();
+    let mut game_time = ticks[0].1.game_time.saturating_sub(1);
+    for (index, (tick_at, state)) in ticks.iter().enumerate() {
+        // The message that shows in the snapshot was the last the tick took in
+        let mut arrivals: Vec<_> = sent
+            .iter()
+            .zip(&targets)
+            .zip(&applied)
+            .filter(|((_, target), _)| **target == Some(index))
+            .collect();
+        arrivals.sort_by_key(|(_, applied)| applied.is_some());
+
+        for ((recorded, _), _) in arrivals {
+            schedule.push((*tick_at, ReplayEvent::Send(recorded.message.clone())));
+        }
+
+        // Ticks whose snapshots never arrived still happened
+        while game_time < state.game_time {
+            schedule.push((*tick_at, ReplayEvent::Tick));
+            game_time += 1;
+        }
+    }
+
+    // Anything sent after the last snapshot couldn't have changed a recorded one
+    schedule
+}
+
+// Input timestamps that only one input has, and so can be matched with a player's last update
+fn unique_timestamps(sent: &[&RecordedMessage]) -> HashSet<u64> {
+    let mut counts = HashMap::new();
+    for recorded in sent {
+        if let GameMessage::PlayerInput { timestamp, .. } = recorded.message {
+            *counts.entry(timestamp).or_insert(0) += 1;
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count == 1)
+        .map(|(timestamp, _)| timestamp)
+        .collect()
+}
+
+// The tick that took in a message, if the snapshots show it: joining is in the first, and an
+// input in the first carrying its timestamp. One overtaken by a later input within the same
+// tick never shows.
+fn applied_at(
+    recorded: &RecordedMessage,
+    ticks: &[(Duration, &GameState)],
+    unique: &HashSet<u64>,
+) -> Option<usize> {
+    match recorded.message {
+        GameMessage::PlayerJoin(_) => Some(0),
+        GameMessage::PlayerInput {
+            player_id,
+            timestamp,
+            ..
+        } if unique.contains(&timestamp) => ticks.iter().position(|(_, state)| {
+            state
+                .players
+                .get(&player_id)
+                .is_some_and(|player| player.last_update == timestamp)
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::SocketAddr, process, thread, time::Duration};
+
+    use crate::definitions::MemoryNetwork;
+
+    use super::{
+        super::{client::GameClient, host::GameServer, types::Vector2},
+        read_recording, Replay,
+    };
+
+    #[test]
+    fn replays_a_recorded_session() {
+        let path = env::temp_dir().join(format!("dserve-replay-{}.rec", process::id()));
+        let network = MemoryNetwork::new();
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 8000));
+        let mut server = GameServer::with_transport(network.bind(server_addr).unwrap());
+        let mut client = GameClient::with_transport(
+            network
+                .bind(SocketAddr::from(([127, 0, 0, 1], 8001)))
+                .unwrap(),
+        );
+
+        client.record_to(&path).unwrap();
+        client.connect(server_addr).unwrap();
+
+        // A few milliseconds a tick keeps input timestamps apart, as a real client's are
+        for tick in 0..80 {
+            server.update().unwrap();
+            client.update().unwrap();
+            if client.player_id.is_some() {
+                let movement = Vector2 {
+                    x: (tick % 5) as f32,
+                    y: (tick % 3) as f32 - 1.0,
+                };
+                client.send_input(movement).unwrap();
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let recording = read_recording(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let report = Replay::new(&recording).unwrap().run(0.0).unwrap();
+        assert!(report.compared > 50, "only {} snapshots", report.compared);
+        assert!(
+            report.mismatches.is_empty(),
+            "{} of {} snapshots differ, first {:?}",
+            report.mismatches.len(),
+            report.compared,
+            report.mismatches[0]
+        );
+    }
+}
@@ -1,128 +1,130 @@
-use bincode;
-use std::{collections::HashMap, time::Duration};
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "metrics")]
+use dserve::definitions::Metrics;
 
 use dserve::{
-    definitions::NetworkProtocol,
-    game_server::{
-        client::GameClient,
-        types::{GameMessage, GameState, PlayerState, Vector2},
+    definitions::{
+        CompressionConfig, CompressionDictionary, ConditionedTransport, NetworkConditions,
     },
+    game_server::{client::GameClient, host::GameServer, types::Vector2},
+    implementations::{bind_socket, init_logging},
 };
+use tracing::info;
 
-struct GameServer {
-    protocol: NetworkProtocol,
-    state: GameState,
-    clients: HashMap<u32, u32>,
-    next_player_id: u32,
-}
-
-impl GameServer {
-    pub fn new(addr: &str) -> std::io::Result<Self> {
-        Ok(Self {
-            protocol: NetworkProtocol::new(addr)?,
-            state: GameState {
-                players: HashMap::new(),
-                game_time: 0,
-            },
-            clients: HashMap::new(),
-            next_player_id: 1,
-        })
+fn main() -> std::io::Result<()> {
+    init_logging();
+
+    // Both ends share one set of conditions, driven by commands typed into stdin
+    let conditions = Arc::new(Mutex::new(NetworkConditions::default()));
+    NetworkConditions::spawn_console(conditions.clone());
+
+    let mut server = GameServer::with_transport(ConditionedTransport::new(
+        bind_socket("127.0.0.1:8000")?,
+        conditions.clone(),
+        0,
+    ));
+
+    info!("Server started on 127.0.0.1:8000");
+
+    #[cfg(feature = "metrics")]
+    let metrics = {
+        let metrics = Arc::new(Mutex::new(Metrics::new(Instant::now())));
+        let addr = env::var("DSERVE_METRICS").unwrap_or_else(|_| "127.0.0.1:9100".to_string());
+        Metrics::serve(metrics.clone(), &addr)?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        metrics
+    };
+
+    // Record state updates to train a dictionary from, or compress with one trained earlier
+    if let Ok(path) = env::var("DSERVE_CAPTURE") {
+        server.capture_to(&path)?;
+        info!("Capturing state updates to {}", path);
     }
-
-    pub fn update(&mut self) -> std::io::Result<()> {
-        // Update network
-        self.protocol.update()?;
-
-        // Process incoming messages
-        while let Some(packet) = self.protocol.buffer.incoming.pop_front() {
-            print!("packet: {:?}", packet);
-            let message: GameMessage =
-                bincode::deserialize(&packet.data).expect("Failed to deserialize game message");
-
-            match message {
-                GameMessage::PlayerInput {
-                    player_id,
-                    movement,
-                    timestamp,
-                } => {
-                    if let Some(player) = self.state.players.get_mut(&player_id) {
-                        player.velocity = movement;
-                        player.last_update = timestamp;
-                    }
-                }
-                GameMessage::PlayerJoin(client_addr) => {
-                    print!("client_addr: {:?}", client_addr);
-                    let player_id = self.next_player_id;
-                    self.next_player_id += 1;
-
-                    let new_player = PlayerState {
-                        position: Vector2 { x: 0.0, y: 0.0 },
-                        velocity: Vector2 { x: 0.0, y: 0.0 },
-                        health: 100,
-                        last_update: self.state.game_time,
-                    };
-
-                    self.state.players.insert(player_id.clone(), new_player);
-                    self.clients.insert(player_id.clone(), client_addr.clone());
-
-                    let id_message = GameMessage::PlayerIdAssigned(player_id.clone());
-                    let serialized =
-                        bincode::serialize(&id_message).expect("Failed to serialize player ID");
-                    self.protocol.send_reliable(serialized)?;
-                }
-                GameMessage::PlayerLeave(player_id) => {
-                    self.state.players.remove(&player_id);
-                }
-                _ => {}
-            }
-        }
-
-        // Update game state
-        self.update_game_state();
-
-        // Broadcast state to all clients
-        let state_update = GameMessage::StateUpdate(self.state.clone());
-        let serialized = bincode::serialize(&state_update).expect("Failed to serialize game state");
-
-        self.protocol.send_reliable(serialized)?;
-
-        Ok(())
+    // Packets for offline inspection, and in development builds the keys to decrypt them
+    if let Ok(path) = env::var("DSERVE_PCAP") {
+        server.protocol.capture_to(&path)?;
+        info!("Capturing packets to {}", path);
     }
-
-    pub fn update_game_state(&mut self) {
-        self.state.game_time += 1;
-
-        // Update player positions based on velocity
-        for player in self.state.players.values_mut() {
-            player.position.x += player.velocity.x;
-            player.position.y += player.velocity.y;
-        }
+    #[cfg(feature = "keylog")]
+    if let Ok(path) = env::var("DSERVE_KEYLOG") {
+        server.protocol.keylog_to(&path)?;
+        info!("Writing session keys to {}", path);
+    }
+    let compression = match env::var("DSERVE_DICTIONARY") {
+        Ok(path) => CompressionConfig::default()
+            .with_dictionary(Arc::new(CompressionDictionary::load(path)?)),
+        Err(_) => CompressionConfig::default(),
+    };
+    server.protocol.set_compression(compression.clone());
+
+    let mut client = GameClient::with_transport(ConditionedTransport::new(
+        bind_socket("127.0.0.1:8001")?,
+        conditions,
+        1,
+    ));
+
+    client.protocol.set_compression(compression);
+
+    // A message-level recording of the client's session, for replaying with game_replay
+    if let Ok(path) = env::var("DSERVE_RECORD") {
+        client.record_to(&path)?;
+        info!("Recording the client session to {}", path);
     }
-}
-
-fn main() -> std::io::Result<()> {
-    let mut server = GameServer::new("127.0.0.1:8000")?;
-
-    println!("Server started on 127.0.0.1:8000");
-
-    let mut client = GameClient::new("127.0.0.1:8001")?;
 
-    println!("Client server started on 127.0.0.1:8001 attempting to connect to 127.0.0.1:8000");
+    info!("Client server started on 127.0.0.1:8001 attempting to connect to 127.0.0.1:8000");
 
     client.connect("127.0.0.1:8000")?;
 
-    println!("Client connected to server");
+    info!("Client connected to server");
 
-    // let mut buffer = [0u8; 1024];
+    // Using 60fps update rate
+    let tick = Duration::from_millis(16);
+    let mut next_tick = Instant::now();
 
     loop {
-        server.update()?;
-        client.update()?;
+        // Only the server's socket is waited on; the client's traffic is picked up at the
+        // latest by the next wakeup or tick
+        let deadline = [server.protocol.next_wakeup(), client.protocol.next_wakeup()]
+            .into_iter()
+            .flatten()
+            .fold(next_tick, Instant::min);
+        server.protocol.wait(Some(deadline))?;
+        server.protocol.handle_readable()?;
+        client.protocol.handle_readable()?;
+
+        let now = Instant::now();
+        if now >= next_tick {
+            server.tick()?;
+
+            #[cfg(feature = "metrics")]
+            {
+                let finished = Instant::now();
+                let mut metrics = metrics.lock().unwrap();
+                metrics.observe_tick(finished - now);
+                metrics.record(&server.protocol.core, finished);
+                metrics.players = Some(server.state.players.len());
+            }
+
+            client.tick()?;
+
+            if client.player_id.is_some() {
+                client.send_input(Vector2 { x: 1.0, y: 0.0 })?;
+            }
 
-        if client.player_id.is_some() {
-            client.send_input(Vector2 { x: 1.0, y: 0.0 })?;
+            // Ticks missed while stalled are skipped rather than run back to back
+            next_tick += tick;
+            if next_tick < now {
+                next_tick = now + tick;
+            }
         }
-        // Using 60fps update rate
-        std::thread::sleep(Duration::from_millis(16));
+
+        // Runs due timers and sends what this iteration queued
+        server.protocol.handle_timeout()?;
+        client.protocol.handle_timeout()?;
     }
 }
@@ -1,14 +1,15 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 // Game state structures
-#[derive(Serialize, Copy, Deserialize, Clone, Debug)]
+#[derive(Serialize, Copy, Deserialize, Clone, Debug, PartialEq)]
 pub struct Vector2 {
     pub x: f32,
     pub y: f32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerState {
     pub position: Vector2,
     pub velocity: Vector2,
@@ -16,13 +17,13 @@ pub struct PlayerState {
     pub last_update: u64, // Timestamp
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct GameState {
     pub players: HashMap<u32, PlayerState>,
     pub game_time: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum GameMessage {
     StateUpdate(GameState),
     PlayerInput {
@@ -34,3 +35,33 @@ pub enum GameMessage {
     PlayerLeave(u32),
     PlayerIdAssigned(u32),
 }
+
+// Which way a recorded message went, from the client's side
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// One message of a recorded client session
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedMessage {
+    // Since recording started
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub message: GameMessage,
+}
+
+// Most a decoded message may allocate, so a bogus length prefix can't exhaust memory
+const MAX_MESSAGE_SIZE: u64 = 256 * 1024;
+
+impl GameMessage {
+    // Same encoding as `bincode::deserialize`, but bounded
+    pub fn decode(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_MESSAGE_SIZE)
+            .deserialize(bytes)
+    }
+}
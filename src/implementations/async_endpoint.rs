@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{self, SocketAddr},
+};
+
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time,
+};
+
+use tracing::{error, warn};
+
+use crate::{
+    definitions::{Connection, ConnectionStats, Endpoint, EndpointStats, NetworkProtocol},
+    enums::{DeliveryEvent, EndpointCommand, Payload, ProtocolEvent},
+    implementations::network_protocol::is_transient,
+};
+
+// Messages and delivery events buffered for each connection
+const CHANNEL_CAPACITY: usize = 1024;
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "endpoint task has stopped")
+}
+
+// A full channel turns receipts into a count, reported as `Unreported` once there is room
+fn report_unreported(events: &mpsc::Sender<DeliveryEvent>, unreported: &mut u64) {
+    if *unreported > 0
+        && events
+            .try_send(DeliveryEvent::Unreported(*unreported))
+            .is_ok()
+    {
+        *unreported = 0;
+    }
+}
+
+fn send_event(events: &mpsc::Sender<DeliveryEvent>, unreported: &mut u64, event: DeliveryEvent) {
+    report_unreported(events, unreported);
+    if *unreported > 0 || events.try_send(event).is_err() {
+        *unreported += match event {
+            DeliveryEvent::Unreported(count) => count,
+            _ => 1,
+        };
+    }
+}
+
+// Errors that only cost a datagram are logged, and the driver keeps going
+fn checked(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if is_transient(&e) => {
+            warn!("endpoint socket error: {}", e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+impl Endpoint {
+    // Spawns the driver task, so this must be called from within a tokio runtime
+    pub fn bind<A: net::ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let protocol = NetworkProtocol::new(addr)?;
+        let local_addr = protocol.transport.local_addr()?;
+        let socket = UdpSocket::from_std(protocol.transport.try_clone()?)?;
+
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (accepted_tx, accepted) = mpsc::unbounded_channel();
+
+        tokio::spawn(drive(
+            protocol,
+            socket,
+            commands.downgrade(),
+            command_rx,
+            accepted_tx,
+        ));
+
+        Ok(Self {
+            local_addr,
+            commands,
+            accepted,
+        })
+    }
+
+    pub async fn connect<A: tokio::net::ToSocketAddrs>(
+        &self,
+        remote_addr: A,
+    ) -> io::Result<Connection> {
+        let remote_addrs = lookup_host(remote_addr).await?.collect();
+        let (reply, response) = oneshot::channel();
+
+        self.commands
+            .send(EndpointCommand::Connect {
+                remote_addrs,
+                reply,
+            })
+            .map_err(|_| stopped())?;
+
+        response.await.map_err(|_| stopped())?
+    }
+
+    pub async fn accept(&mut self) -> Option<Connection> {
+        self.accepted.recv().await
+    }
+
+    pub async fn stats(&self) -> io::Result<EndpointStats> {
+        let (reply, response) = oneshot::channel();
+
+        self.commands
+            .send(EndpointCommand::EndpointStats { reply })
+            .map_err(|_| stopped())?;
+
+        response.await.map_err(|_| stopped())
+    }
+}
+
+impl Connection {
+    pub async fn send(&self, data: Vec<u8>) -> io::Result<u32> {
+        self.request_send(data, true).await
+    }
+
+    pub async fn send_unreliable(&self, data: Vec<u8>) -> io::Result<u32> {
+        self.request_send(data, false).await
+    }
+
+    async fn request_send(&self, data: Vec<u8>, reliable: bool) -> io::Result<u32> {
+        let (reply, response) = oneshot::channel();
+
+        self.commands
+            .send(EndpointCommand::Send {
+                peer: self.peer,
+                data,
+                reliable,
+                reply,
+            })
+            .map_err(|_| stopped())?;
+
+        response.await.map_err(|_| stopped())?
+    }
+
+    pub async fn stats(&self) -> io::Result<ConnectionStats> {
+        let (reply, response) = oneshot::channel();
+
+        self.commands
+            .send(EndpointCommand::Stats {
+                peer: self.peer,
+                reply,
+            })
+            .map_err(|_| stopped())?;
+
+        response
+            .await
+            .map_err(|_| stopped())?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "connection is closed"))
+    }
+
+    // Returns None once the connection is closed
+    pub async fn recv(&mut self) -> Option<Payload> {
+        self.incoming.recv().await
+    }
+
+    pub async fn next_event(&mut self) -> Option<DeliveryEvent> {
+        self.events.recv().await
+    }
+}
+
+// Nothing awaits the driver task, so its failure is logged here. Returning drops every
+// connection's channels, so `recv` returns None and requests fail with `stopped()`.
+async fn drive(
+    protocol: NetworkProtocol,
+    socket: UdpSocket,
+    commands: mpsc::WeakUnboundedSender<EndpointCommand>,
+    command_rx: mpsc::UnboundedReceiver<EndpointCommand>,
+    accepted: mpsc::UnboundedSender<Connection>,
+) {
+    if let Err(e) = run(protocol, socket, commands, command_rx, accepted).await {
+        error!("endpoint task stopped: {}", e);
+    }
+}
+
+// Owns the protocol, waking on datagrams, handle commands and protocol timers.
+// The tokio socket shares the protocol's file descriptor, so either can read from it.
+async fn run(
+    mut protocol: NetworkProtocol,
+    socket: UdpSocket,
+    commands: mpsc::WeakUnboundedSender<EndpointCommand>,
+    mut command_rx: mpsc::UnboundedReceiver<EndpointCommand>,
+    accepted: mpsc::UnboundedSender<Connection>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 2048];
+    let mut pending_connects: HashMap<SocketAddr, oneshot::Sender<io::Result<Connection>>> =
+        HashMap::new();
+    // Each connection's channels, and how many receipts its full event channel has missed
+    let mut peers: HashMap<SocketAddr, (mpsc::Sender<Payload>, mpsc::Sender<DeliveryEvent>, u64)> =
+        HashMap::new();
+    // Connection handles keep the address they were created with, even after their peer migrates
+    let mut routes: HashMap<SocketAddr, SocketAddr> = HashMap::new();
+
+    loop {
+        let deadline = protocol.next_wakeup().map(time::Instant::from_std);
+
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((size, from)) => checked(protocol.handle_datagram(&mut buf[..size], from))?,
+                Err(e) => checked(Err(e))?,
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() => {}
+            command = command_rx.recv() => match command {
+                Some(EndpointCommand::Connect { remote_addrs, reply }) => {
+                    match protocol.connect(&remote_addrs[..]) {
+                        Ok(peer) => {
+                            pending_connects.insert(peer, reply);
+                        }
+                        Err(e) => {
+                            let _ = reply.send(Err(e));
+                        }
+                    }
+                }
+                Some(EndpointCommand::Send { peer, data, reliable, reply }) => {
+                    let peer = routes.get(&peer).copied().unwrap_or(peer);
+                    let result = if reliable {
+                        protocol.send_reliable(peer, data)
+                    } else {
+                        protocol.send_unreliable(peer, data)
+                    };
+                    let _ = reply.send(result);
+                }
+                Some(EndpointCommand::Stats { peer, reply }) => {
+                    let peer = routes.get(&peer).copied().unwrap_or(peer);
+                    let _ = reply.send(protocol.stats(peer));
+                }
+                Some(EndpointCommand::EndpointStats { reply }) => {
+                    let _ = reply.send(protocol.endpoint_stats());
+                }
+                // Every handle has been dropped
+                None => return Ok(()),
+            }
+        }
+
+        checked(protocol.update())?;
+
+        while let Some(event) = protocol.poll_event() {
+            match event {
+                ProtocolEvent::Connected(peer) => {
+                    let Some(commands) = commands.upgrade() else {
+                        return Ok(());
+                    };
+
+                    let (incoming_tx, incoming) = mpsc::channel(CHANNEL_CAPACITY);
+                    let (events_tx, events) = mpsc::channel(CHANNEL_CAPACITY);
+                    let connection = Connection {
+                        peer,
+                        commands,
+                        incoming,
+                        events,
+                    };
+
+                    peers.insert(peer, (incoming_tx, events_tx, 0));
+                    // The address may have belonged to a connection that moved away
+                    routes.remove(&peer);
+
+                    match pending_connects.remove(&peer) {
+                        Some(reply) => {
+                            let _ = reply.send(Ok(connection));
+                        }
+                        None => {
+                            let _ = accepted.send(connection);
+                        }
+                    }
+                }
+                ProtocolEvent::Disconnected(peer, _) => {
+                    if let Some(reply) = pending_connects.remove(&peer) {
+                        let _ = reply.send(Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "handshake timed out",
+                        )));
+                    }
+
+                    // Closes the connection's channels
+                    peers.remove(&peer);
+                    routes.retain(|_, current| *current != peer);
+                }
+                ProtocolEvent::Migrated(from, to) => {
+                    if let Some(channels) = peers.remove(&from) {
+                        peers.insert(to, channels);
+                    }
+
+                    // A connection that already moved once has its handle address elsewhere
+                    let mut routed = false;
+                    for current in routes.values_mut().filter(|current| **current == from) {
+                        *current = to;
+                        routed = true;
+                    }
+                    if !routed {
+                        routes.insert(from, to);
+                    }
+                }
+                // The endpoint never registers with an introducer
+                ProtocolEvent::Registered(_)
+                | ProtocolEvent::PunchSucceeded(..)
+                | ProtocolEvent::Relayed(..)
+                | ProtocolEvent::PunchFailed(_) => {}
+                ProtocolEvent::Delivery(peer, event) => {
+                    if let Some((_, events, unreported)) = peers.get_mut(&peer) {
+                        send_event(events, unreported, event);
+                    }
+                }
+            }
+        }
+
+        for (peer, (incoming, events, unreported)) in &mut peers {
+            report_unreported(events, unreported);
+
+            // A full channel leaves messages in the session's queue, and once that fills the
+            // session stops acking, so the peer backs off and retransmits
+            loop {
+                match incoming.try_reserve() {
+                    Ok(permit) => match protocol.poll_message_from(*peer) {
+                        Some(packet) => permit.send(packet.data),
+                        None => break,
+                    },
+                    Err(TrySendError::Full(())) => break,
+                    // The handle was dropped, so nobody will read them
+                    Err(TrySendError::Closed(())) => {
+                        while protocol.poll_message_from(*peer).is_some() {}
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::{
+        definitions::{Connection, Endpoint},
+        enums::DeliveryEvent,
+    };
+
+    // Server and client endpoints on loopback, with the client's connection and the server's
+    // side of it
+    async fn connected() -> (Endpoint, Endpoint, Connection, Connection) {
+        let mut server = Endpoint::bind("127.0.0.1:0").unwrap();
+        let client = Endpoint::bind("127.0.0.1:0").unwrap();
+
+        let outgoing = client.connect(server.local_addr).await.unwrap();
+        let incoming = server.accept().await.unwrap();
+        (server, client, outgoing, incoming)
+    }
+
+    #[tokio::test]
+    async fn delivers_a_message_and_its_receipt() {
+        timeout(Duration::from_secs(10), async {
+            let (_server, _client, mut outgoing, mut incoming) = connected().await;
+
+            let message_id = outgoing.send(b"over loopback".to_vec()).await.unwrap();
+
+            assert_eq!(&*incoming.recv().await.unwrap(), b"over loopback");
+            assert_eq!(
+                outgoing.next_event().await,
+                Some(DeliveryEvent::Delivered(message_id))
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_receipts_past_a_full_event_channel() {
+        timeout(Duration::from_secs(30), async {
+            let (_server, _client, mut outgoing, mut incoming) = connected().await;
+
+            // More receipts than the connection's event channel holds, none of them read
+            let count = super::CHANNEL_CAPACITY as u64 + 100;
+            let reader = tokio::spawn(async move {
+                for _ in 0..count {
+                    incoming.recv().await.unwrap();
+                }
+                incoming
+            });
+            for i in 0..count {
+                outgoing.send(i.to_be_bytes().to_vec()).await.unwrap();
+            }
+            let _incoming = reader.await.unwrap();
+
+            let (mut delivered, mut unreported) = (0, 0);
+            while delivered + unreported < count {
+                match outgoing.next_event().await.unwrap() {
+                    DeliveryEvent::Delivered(_) => delivered += 1,
+                    DeliveryEvent::Unreported(n) => unreported += n,
+                    DeliveryEvent::Lost(id) => panic!("lost message {}", id),
+                }
+            }
+            assert!(unreported > 0);
+        })
+        .await
+        .unwrap();
+    }
+}
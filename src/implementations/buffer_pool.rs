@@ -0,0 +1,105 @@
+use std::{
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use crate::definitions::{BufferPool, PooledBuffer};
+
+// Fits any datagram the protocol reads, and most decompressed messages
+const DEFAULT_CAPACITY: usize = 2048;
+// Keeps one unusually large message from pinning its buffer forever
+const DEFAULT_MAX_CAPACITY: usize = 64 * 1024;
+const DEFAULT_MAX_BUFFERS: usize = 1024;
+
+impl BufferPool {
+    pub fn new(capacity: usize, max_capacity: usize, max_buffers: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            capacity,
+            max_capacity,
+            max_buffers,
+        }
+    }
+
+    // An empty buffer, reused when one is available
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.capacity))
+    }
+
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > self.max_capacity {
+            return;
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+
+    // Like `take`, but the buffer finds its own way back when dropped
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        PooledBuffer {
+            buffer: self.take(),
+            pool: self.clone(),
+        }
+    }
+
+    // Buffers waiting to be reused
+    pub fn available(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, DEFAULT_MAX_CAPACITY, DEFAULT_MAX_BUFFERS)
+    }
+}
+
+impl PooledBuffer {
+    // Keeps the bytes, leaving the pool to allocate a replacement later
+    pub fn into_vec(mut self) -> Vec<u8> {
+        mem::take(&mut self.buffer)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.buffer));
+    }
+}
+
+impl Clone for PooledBuffer {
+    fn clone(&self) -> Self {
+        let mut buffer = self.pool.get();
+        buffer.extend_from_slice(&self.buffer);
+        buffer
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.buffer.fmt(f)
+    }
+}
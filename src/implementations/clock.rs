@@ -0,0 +1,43 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::definitions::{Clock, SystemClock, VirtualClock};
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl VirtualClock {
+    // Starts at the current instant, after which only `advance` and `set` move it
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    // Never moves backwards, as a monotonic clock wouldn't
+    pub fn set(&self, instant: Instant) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(instant);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
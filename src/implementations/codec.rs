@@ -0,0 +1,150 @@
+use std::io::{self, Write};
+
+use flate2::{
+    write::{ZlibDecoder, ZlibEncoder},
+    Compression,
+};
+use zstd::stream::{read::Decoder, write::Encoder};
+
+use crate::{definitions::LimitedWriter, enums::Codec};
+
+// The smallest and largest windows zstd has, on 64-bit targets
+const ZSTD_WINDOW_LOG_MIN: u32 = 10;
+const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+
+// The window a zstd frame needs for `limit` bytes of content
+fn window_log(limit: usize) -> u32 {
+    let log = usize::BITS - limit.saturating_sub(1).leading_zeros();
+    log.clamp(ZSTD_WINDOW_LOG_MIN, ZSTD_WINDOW_LOG_MAX)
+}
+
+impl Codec {
+    // The algorithm behind a handshake id, at its default level
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::None),
+            1 => Some(Self::Zlib(6)),
+            2 => Some(Self::Lz4),
+            3 => Some(Self::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+            _ => None,
+        }
+    }
+
+    // Appends the compressed form of `data` to `output`. Zlib ignores the dictionary: the
+    // pure Rust backend has no preset dictionaries.
+    pub fn compress(
+        &self,
+        data: &[u8],
+        dictionary: Option<&[u8]>,
+        output: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        match (self, dictionary) {
+            (Self::None, _) => output.extend_from_slice(data),
+            (Self::Zlib(level), _) => {
+                let mut encoder = ZlibEncoder::new(output, Compression::new(*level));
+                encoder.write_all(data)?;
+                encoder.finish()?;
+            }
+            (Self::Lz4, None) => output.extend_from_slice(&lz4_flex::compress_prepend_size(data)),
+            (Self::Lz4, Some(dictionary)) => output.extend_from_slice(
+                &lz4_flex::block::compress_prepend_size_with_dict(data, dictionary),
+            ),
+            (Self::Zstd(level), dictionary) => {
+                let mut encoder = match dictionary {
+                    Some(dictionary) => Encoder::with_dictionary(output, *level, dictionary)?,
+                    None => Encoder::new(output, *level)?,
+                };
+                // Sizes the window to the message, so it fits the receiver's smaller one
+                encoder.set_pledged_src_size(Some(data.len() as u64))?;
+                encoder.write_all(data)?;
+                encoder.finish()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Needs the dictionary `data` was compressed with. Fails with `LimitedWriter`'s error,
+    // without decompressing the rest, as soon as the output would pass `limit` bytes.
+    pub fn decompress(
+        &self,
+        data: &[u8],
+        dictionary: Option<&[u8]>,
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        let invalid = |e: Box<dyn std::error::Error + Send + Sync>| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        };
+        let mut limited = LimitedWriter::new(output, limit);
+
+        match (self, dictionary) {
+            (Self::None, _) => limited.write_all(data)?,
+            (Self::Zlib(_), _) => {
+                let mut decoder = ZlibDecoder::new(limited);
+                decoder.write_all(data)?;
+                decoder.finish()?;
+            }
+            // The size comes first, so an oversized message is turned down before allocating
+            (Self::Lz4, dictionary) => {
+                let size = data
+                    .get(..4)
+                    .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid("truncated LZ4 message".into()))?;
+                if size > limit {
+                    return Err(LimitedWriter::limit_exceeded());
+                }
+
+                let decompressed = match dictionary {
+                    Some(dictionary) => {
+                        lz4_flex::block::decompress_size_prepended_with_dict(data, dictionary)
+                    }
+                    None => lz4_flex::decompress_size_prepended(data),
+                };
+                limited.write_all(&decompressed.map_err(|e| invalid(e.into()))?)?;
+            }
+            // The window is allocated from the frame header, so a tiny frame could otherwise
+            // claim zstd's default maximum. Reading to the end fails on a truncated frame.
+            (Self::Zstd(_), dictionary) => {
+                // Like LZ4's, a size in the header turns an oversized message down at once
+                if let Ok(Some(size)) = zstd::zstd_safe::get_frame_content_size(data) {
+                    if size > limit as u64 {
+                        return Err(LimitedWriter::limit_exceeded());
+                    }
+                }
+
+                let mut decoder = Decoder::with_dictionary(data, dictionary.unwrap_or_default())?;
+                decoder.window_log_max(window_log(limit))?;
+                io::copy(&mut decoder, &mut limited)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::enums::Codec;
+
+    #[test]
+    fn zstd_refuses_a_truncated_frame() {
+        let message: Vec<u8> = (0..4096u32).map(|i| (i * i % 251) as u8).collect();
+        let mut compressed = Vec::new();
+        Codec::Zstd(3)
+            .compress(&message, None, &mut compressed)
+            .unwrap();
+
+        let mut output = Vec::new();
+        Codec::Zstd(3)
+            .decompress(&compressed, None, &mut output, message.len())
+            .unwrap();
+        assert_eq!(output, message);
+
+        let truncated = &compressed[..compressed.len() - 4];
+        let mut output = Vec::new();
+        assert!(Codec::Zstd(3)
+            .decompress(truncated, None, &mut output, message.len())
+            .is_err());
+    }
+}
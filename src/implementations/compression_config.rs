@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use crate::{
+    definitions::{CompressionConfig, CompressionDictionary, NegotiatedCompression},
+    enums::Codec,
+};
+
+impl CompressionConfig {
+    // No compression on either channel
+    pub fn none() -> Self {
+        Self {
+            reliable: Vec::new(),
+            unreliable: Vec::new(),
+            threshold: usize::MAX,
+            dictionary: None,
+            streaming: false,
+        }
+    }
+
+    pub fn with_dictionary(mut self, dictionary: Arc<CompressionDictionary>) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    // Appended to a Connect: for each channel a count and then the codec ids, followed by
+    // the dictionary id, or zero without one, and whether we'd stream
+    pub fn encode_offer(&self, out: &mut Vec<u8>) {
+        for codecs in [&self.reliable, &self.unreliable] {
+            out.push(codecs.len() as u8);
+            out.extend(codecs.iter().map(Codec::id));
+        }
+        out.extend_from_slice(&self.dictionary_id().to_be_bytes());
+        out.push(self.streaming as u8);
+    }
+
+    // What to use for the offer at the start of `offer`: for each channel the first codec we
+    // also have, our dictionary if the peer has the same one, and streaming if both want it.
+    // A peer that offers nothing gets no compression.
+    pub fn choose(&self, offer: &[u8]) -> NegotiatedCompression {
+        let mut rest = offer;
+        let mut channel = |supported: &[Codec]| {
+            let Some((&count, ids)) = rest.split_first() else {
+                return Codec::None;
+            };
+            let ids = &ids[..(count as usize).min(ids.len())];
+            rest = &rest[1 + ids.len()..];
+
+            ids.iter()
+                .find_map(|id| supported.iter().find(|codec| codec.id() == *id))
+                .copied()
+                .unwrap_or(Codec::None)
+        };
+
+        let reliable = channel(&self.reliable);
+        let unreliable = channel(&self.unreliable);
+        let dictionary = rest
+            .get(..4)
+            .and_then(|id| self.dictionary(u32::from_be_bytes(id.try_into().unwrap())));
+        let streaming = self.streaming && rest.get(4).is_some_and(|streaming| *streaming != 0);
+
+        NegotiatedCompression {
+            reliable,
+            unreliable,
+            dictionary,
+            streaming,
+        }
+    }
+
+    // Reads what the peer chose for our offer from an Accept, or None if it chose something
+    // we never offered
+    pub fn accept(&self, chosen: &[u8]) -> Option<NegotiatedCompression> {
+        let chosen = chosen.get(..NegotiatedCompression::ENCODED_LEN)?;
+
+        let dictionary = match u32::from_be_bytes(chosen[2..6].try_into().unwrap()) {
+            0 => None,
+            id => Some(self.dictionary(id)?),
+        };
+        let streaming = chosen[6] != 0;
+        if streaming && !self.streaming {
+            return None;
+        }
+
+        Some(NegotiatedCompression {
+            reliable: self.codec(chosen[0], true)?,
+            unreliable: self.codec(chosen[1], false)?,
+            dictionary,
+            streaming,
+        })
+    }
+
+    // The zlib level the compression stream runs at
+    pub fn stream_level(&self) -> u32 {
+        self.reliable
+            .iter()
+            .find_map(|codec| match codec {
+                Codec::Zlib(level) => Some(*level),
+                _ => None,
+            })
+            .unwrap_or(6)
+    }
+
+    // Our own setting for a codec the peer chose, if we offered it on that channel
+    pub fn codec(&self, id: u8, reliable: bool) -> Option<Codec> {
+        let codecs = if reliable {
+            &self.reliable
+        } else {
+            &self.unreliable
+        };
+
+        if id == Codec::None.id() {
+            return Some(Codec::None);
+        }
+        codecs.iter().find(|codec| codec.id() == id).copied()
+    }
+
+    // Our dictionary, if it is the one with this id
+    pub fn dictionary(&self, id: u32) -> Option<Arc<CompressionDictionary>> {
+        self.dictionary
+            .clone()
+            .filter(|dictionary| id != 0 && dictionary.id == id)
+    }
+
+    pub fn dictionary_id(&self) -> u32 {
+        self.dictionary
+            .as_ref()
+            .map_or(0, |dictionary| dictionary.id)
+    }
+}
+
+// Reliable traffic is often bulky and worth zstd's ratio; unreliable traffic is latency
+// sensitive, so it only gets LZ4
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            reliable: vec![Codec::Zstd(3), Codec::Lz4, Codec::Zlib(6)],
+            unreliable: vec![Codec::Lz4],
+            threshold: 64,
+            dictionary: None,
+            streaming: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{definitions::CompressionConfig, enums::Codec};
+
+    #[test]
+    fn accept_refuses_what_we_didnt_offer() {
+        let ours = CompressionConfig {
+            reliable: vec![Codec::Lz4],
+            ..CompressionConfig::default()
+        };
+
+        // reliable, unreliable, dictionary id, streaming
+        assert!(ours.accept(&[2, 2, 0, 0, 0, 0, 0]).is_some());
+        assert!(ours.accept(&[0, 0, 0, 0, 0, 0, 0]).is_some());
+        assert!(ours.accept(&[3, 2, 0, 0, 0, 0, 0]).is_none());
+        assert!(ours.accept(&[2, 9, 0, 0, 0, 0, 0]).is_none());
+        assert!(ours.accept(&[2, 2, 0, 0, 0, 7, 0]).is_none());
+        assert!(ours.accept(&[2, 2, 0, 0, 0, 0, 1]).is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::definitions::CompressionDictionary;
+
+impl CompressionDictionary {
+    // Zero is left for "no dictionary"
+    pub fn new(id: u32, bytes: Vec<u8>) -> io::Result<Self> {
+        if id == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dictionary ids start at 1",
+            ));
+        }
+
+        Ok(Self { id, bytes })
+    }
+
+    // Builds a dictionary of up to `max_size` bytes from typical messages. Training needs a
+    // good number of samples, a few hundred at least.
+    pub fn train(id: u32, samples: &[Vec<u8>], max_size: usize) -> io::Result<Self> {
+        Self::new(id, zstd::dict::from_samples(samples, max_size)?)
+    }
+
+    // The id, then the dictionary itself
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(&self.id.to_be_bytes())?;
+        file.write_all(&self.bytes)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        if contents.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dictionary file is too short",
+            ));
+        }
+
+        let (id, bytes) = contents.split_at(4);
+        Self::new(u32::from_be_bytes(id.try_into().unwrap()), bytes.to_vec())
+    }
+}
+
+// Captures are a series of samples, each prefixed with its length as a big-endian u32
+pub fn write_sample<W: Write>(capture: &mut W, sample: &[u8]) -> io::Result<()> {
+    capture.write_all(&(sample.len() as u32).to_be_bytes())?;
+    capture.write_all(sample)
+}
+
+pub fn read_samples<R: Read>(capture: &mut R) -> io::Result<Vec<Vec<u8>>> {
+    let mut samples = Vec::new();
+    let mut len = [0u8; 4];
+
+    loop {
+        match capture.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(samples),
+            Err(e) => return Err(e),
+        }
+
+        let mut sample = vec![0u8; u32::from_be_bytes(len) as usize];
+        capture.read_exact(&mut sample)?;
+        samples.push(sample);
+    }
+}
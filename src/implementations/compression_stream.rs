@@ -0,0 +1,96 @@
+use std::{collections::BTreeMap, io};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+use crate::definitions::{CompressionStream, LimitedWriter};
+
+// Every sync flush ends with these bytes, so they are left off the wire and put back on receipt
+const SYNC_FLUSH_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+// Extra room given to the output on each pass of the compressor
+const OUTPUT_STEP: usize = 256;
+
+impl CompressionStream {
+    pub fn new(level: u32) -> Self {
+        Self {
+            compress: Compress::new(Compression::new(level), false),
+            decompress: Decompress::new(false),
+            next_sent: 0,
+            next_received: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    // Appends the message's position in the stream, then its compressed form
+    pub fn compress(&mut self, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        output.extend_from_slice(&self.next_sent.to_be_bytes());
+        self.next_sent = self.next_sent.wrapping_add(1);
+
+        let start = self.compress.total_in();
+        loop {
+            output.reserve(OUTPUT_STEP);
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+
+            // Done once all input is in and the flush didn't run out of room
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&SYNC_FLUSH_TAIL) {
+            output.truncate(output.len() - SYNC_FLUSH_TAIL.len());
+        }
+        Ok(())
+    }
+
+    // The position a streamed message was sent at
+    pub fn position(data: &[u8]) -> Option<u32> {
+        data.get(..4)
+            .map(|position| u32::from_be_bytes(position.try_into().unwrap()))
+    }
+
+    // Decompresses the next message in the stream, giving up once it grows past `limit` bytes.
+    // An error leaves the stream unusable.
+    pub fn decompress(
+        &mut self,
+        data: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        self.next_received = self.next_received.wrapping_add(1);
+
+        let mut input = data.get(4..).unwrap_or_default().to_vec();
+        input.extend_from_slice(&SYNC_FLUSH_TAIL);
+
+        let start = self.decompress.total_in();
+        loop {
+            output.reserve(OUTPUT_STEP);
+            let progress = (self.decompress.total_in(), self.decompress.total_out());
+            let consumed = (self.decompress.total_in() - start) as usize;
+            self.decompress
+                .decompress_vec(&input[consumed..], output, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            if output.len() > limit {
+                return Err(LimitedWriter::limit_exceeded());
+            }
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(());
+            }
+
+            // A stream that was ended takes nothing more
+            if (self.decompress.total_in(), self.decompress.total_out()) == progress {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compression stream stalled",
+                ));
+            }
+        }
+    }
+}
@@ -1,15 +1,20 @@
 use std::time::{Duration, Instant};
 
+use tracing::{debug, trace};
+
 use crate::definitions::CongestionControl;
 
+// Acks are only sent on the next flush, so a very short RTT would trigger spurious resends
+const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(25);
+
 impl CongestionControl {
-    pub fn new() -> Self {
+    pub fn new(now: Instant) -> Self {
         Self {
             window_size: 1,
             threshold: 16,
             rtt: Duration::from_millis(100),
             rtt_var: Duration::from_millis(50),
-            last_window_decrease: Instant::now(),
+            last_window_decrease: now,
         }
     }
 
@@ -19,26 +24,36 @@ impl CongestionControl {
         } else {
             self.window_size += 1 / self.window_size
         }
+        trace!(window = self.window_size, "congestion window grew");
     }
 
-    pub fn on_loss(&mut self) {
+    pub fn on_loss(&mut self, now: Instant) {
         self.threshold = self.window_size / 2;
         self.window_size = 1;
-        self.last_window_decrease = Instant::now();
+        self.last_window_decrease = now;
+        debug!(
+            window = self.window_size,
+            threshold = self.threshold,
+            "congestion window cut after loss"
+        );
     }
 
-    pub fn update_rrt(&mut self, measured_rtt: Duration) {
-        const ALPHA: f32 = 0.125;
-        const BETA: f32 = 0.25;
+    // Folds in an RTT sample the way TCP does (RFC 6298): the variance moves towards how far
+    // the sample is from the smoothed RTT, then the smoothed RTT towards the sample
+    pub fn update_rtt(&mut self, measured_rtt: Duration) {
+        const ALPHA: f64 = 0.125;
+        const BETA: f64 = 0.25;
 
-        let rrt_ms = self.rtt.as_secs_f32() * 1000.0;
-        let measured_rtt_ms = measured_rtt.as_secs_f32() * 1000.0;
-        let diff = measured_rtt_ms - rrt_ms;
+        let rtt = self.rtt.as_secs_f64();
+        let rtt_var = self.rtt_var.as_secs_f64();
+        let diff = measured_rtt.as_secs_f64() - rtt;
 
-        self.rtt = Duration::from_secs_f32((rrt_ms + ALPHA * diff) / 1000.0);
-        self.rtt_var = Duration::from_secs_f32(
-            (self.rtt_var.as_secs_f32() + BETA * (diff.abs() - self.rtt_var.as_secs_f32()))
-                / 1000.0,
-        );
+        self.rtt_var = Duration::from_secs_f64(rtt_var + BETA * (diff.abs() - rtt_var));
+        self.rtt = Duration::from_secs_f64(rtt + ALPHA * diff);
+    }
+
+    // How long an unacked packet is given before it is resent
+    pub fn retransmit_timeout(&self) -> Duration {
+        (self.rtt + self.rtt_var * 4).max(MIN_RETRANSMIT_TIMEOUT)
     }
 }
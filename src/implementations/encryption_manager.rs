@@ -1,67 +1,140 @@
-use ring::{
-    aead,
-    rand::{self, SecureRandom},
-};
+use ring::{aead, agreement, error::Unspecified, hkdf, rand};
 
 use crate::definitions::EncryptionManager;
 
+const KEY_SALT: &[u8] = b"dserve handshake";
+const KEY_INFO: &[u8] = b"dserve session key";
+// ChaCha20-Poly1305
+const KEY_LEN: usize = 32;
+
+// Asks HKDF for raw key bytes, which unlike an `aead::UnboundKey` can be exported
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
 impl EncryptionManager {
     pub fn new() -> Self {
-        const KEY_LEN: usize = 32;
         let rng = rand::SystemRandom::new();
-        let mut key_bytes = [0u8; KEY_LEN];
-
-        rng.fill(&mut key_bytes).expect("Failed to generate key");
 
-        // let key_bytes = rand::generate(&rng, &aead::CHACHA20_POLY1305.key_len())
-        //     .expect("Failed to generate key");
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+            .expect("Failed to generate key");
+        let public_key = private_key
+            .compute_public_key()
+            .expect("Failed to compute public key")
+            .as_ref()
+            .to_vec();
 
         Self {
-            key: aead::LessSafeKey::new(
-                aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &mut key_bytes.as_ref())
-                    .expect("Failed to create key"),
-            ),
-            nonce_sequence: 0,
+            key: None,
+            private_key: Some(private_key),
+            public_key,
+            initiator: false,
+            #[cfg(feature = "keylog")]
+            export_key: false,
+            #[cfg(feature = "keylog")]
+            exported_key: None,
+        }
+    }
+
+    // Skips the handshake and uses a key that is already known, such as one from a keylog
+    pub fn with_key(key: &[u8], initiator: bool) -> Result<Self, Unspecified> {
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)?;
+
+        Ok(Self {
+            key: Some(aead::LessSafeKey::new(key)),
+            private_key: None,
+            public_key: Vec::new(),
+            initiator,
+            #[cfg(feature = "keylog")]
+            export_key: false,
+            #[cfg(feature = "keylog")]
+            exported_key: None,
+        })
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.key.is_some()
+    }
+
+    // Derives the session key from the peer's handshake public key
+    pub fn establish(
+        &mut self,
+        peer_public_key: &[u8],
+        initiator: bool,
+    ) -> Result<(), Unspecified> {
+        let private_key = self.private_key.take().ok_or(Unspecified)?;
+        let peer_public_key =
+            agreement::UnparsedPublicKey::new(&agreement::X25519, peer_public_key);
+
+        let key = agreement::agree_ephemeral(private_key, &peer_public_key, |secret| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, KEY_SALT).extract(secret);
+            let mut key = [0u8; KEY_LEN];
+            prk.expand(&[KEY_INFO], KeyLen(KEY_LEN))?.fill(&mut key)?;
+            Ok::<_, Unspecified>(key)
+        })??;
+
+        #[cfg(feature = "keylog")]
+        if self.export_key {
+            self.exported_key = Some(key);
         }
+
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key)?;
+        self.key = Some(aead::LessSafeKey::new(key));
+        self.initiator = initiator;
+        Ok(())
+    }
+
+    // Both directions share the key, so the first nonce byte keeps them apart
+    fn nonce(initiator: bool, sequence: u32) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[0] = initiator as u8;
+        nonce[8..].copy_from_slice(&sequence.to_be_bytes());
+        aead::Nonce::assume_unique_for_key(nonce)
     }
 
-    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
-        let nonce = aead::Nonce::assume_unique_for_key([
-            0u8,
-            0,
-            0,
-            0,
-            ((self.nonce_sequence >> 32) & 0xff) as u8,
-            ((self.nonce_sequence >> 24) & 0xff) as u8,
-            ((self.nonce_sequence >> 16) & 0xff) as u8,
-            ((self.nonce_sequence >> 8) & 0xff) as u8,
-            (self.nonce_sequence & 0xff) as u8,
-            0,
-            0,
-            0,
-        ]);
-
-        self.nonce_sequence += 1;
-
-        let mut in_out = data.to_vec();
-        self.key
-            .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut in_out)
-            .expect("Failed to encrypt");
-        in_out
+    // Encrypts everything after the header in place and appends the tag, so a datagram is
+    // sealed in the buffer it was assembled in
+    pub fn encrypt_in_place(
+        &self,
+        sequence: u32,
+        datagram: &mut Vec<u8>,
+        header_len: usize,
+    ) -> Result<(), Unspecified> {
+        let key = self.key.as_ref().ok_or(Unspecified)?;
+        let nonce = Self::nonce(self.initiator, sequence);
+
+        let (header, body) = datagram.split_at_mut(header_len);
+        let tag = key.seal_in_place_separate_tag(nonce, aead::Aad::from(&*header), body)?;
+        datagram.extend_from_slice(tag.as_ref());
+        Ok(())
     }
 
-    pub fn decrypt(&self, encrypted: &mut [u8]) -> Result<Vec<u8>, ()> {
+    // Returns the plaintext, which overwrites the start of `encrypted`
+    pub fn decrypt_in_place<'a>(
+        &self,
+        sequence: u32,
+        header: &[u8],
+        encrypted: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Unspecified> {
+        let key = self.key.as_ref().ok_or(Unspecified)?;
+
         // Check if the length of the encrypted data is valid
         if encrypted.len() < aead::CHACHA20_POLY1305.tag_len() {
-            return Err(()); // Return an error if the buffer is too small
+            return Err(Unspecified);
         }
 
-        let tag_pos = encrypted.len() - aead::CHACHA20_POLY1305.tag_len();
-        let nonce = aead::Nonce::assume_unique_for_key([0u8; 12]);
+        let nonce = Self::nonce(!self.initiator, sequence);
+
+        key.open_in_place(nonce, aead::Aad::from(header), encrypted)
+    }
+}
 
-        self.key
-            .open_in_place(nonce, aead::Aad::empty(), encrypted)
-            .map_err(|_| ())
-            .map(|decrypted| decrypted.to_vec())
+impl Default for EncryptionManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
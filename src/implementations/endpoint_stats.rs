@@ -0,0 +1,16 @@
+use crate::definitions::{ConnectionStats, EndpointStats};
+
+impl EndpointStats {
+    // Adds a connection's counters to the totals
+    pub fn add(&mut self, stats: &ConnectionStats) {
+        self.bytes_sent += stats.bytes_sent;
+        self.bytes_received += stats.bytes_received;
+        self.packets_sent += stats.packets_sent;
+        self.packets_received += stats.packets_received;
+        self.retransmissions += stats.retransmissions;
+        self.packets_acked += stats.packets_acked;
+        self.packets_lost += stats.packets_lost;
+        self.decrypt_failures += stats.decrypt_failures;
+        self.duplicates += stats.duplicates;
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    definitions::{
+        CapturedDatagram, CompressionConfig, CompressionDictionary, CompressionStream,
+        EncryptionManager, InspectedConnection, InspectedPacket, Inspector, MessageLimits,
+        NegotiatedCompression, Packet,
+    },
+    enums::{Codec, PacketKind, ProtocolState, RendezvousMessage},
+    implementations::HEADER_SIZE,
+};
+
+// X25519, at the start of a Connect or Accept body
+const PUBLIC_KEY_LEN: usize = 32;
+// Only the sender's level matters, and the inspector never compresses
+const STREAM_LEVEL: u32 = 6;
+
+impl Inspector {
+    pub fn new(
+        keys: HashMap<u64, [u8; 32]>,
+        dictionary: Option<Arc<CompressionDictionary>>,
+    ) -> Self {
+        // With no codecs of its own, the config takes whatever a peer chose
+        let compression = CompressionConfig {
+            reliable: Vec::new(),
+            unreliable: Vec::new(),
+            threshold: usize::MAX,
+            dictionary,
+            streaming: true,
+        };
+
+        Self {
+            keys,
+            compression,
+            limits: MessageLimits::default(),
+            connections: BTreeMap::new(),
+        }
+    }
+
+    // Datagrams have to be fed in the order they were captured, for the handshakes and
+    // compression streams to be followed
+    pub fn inspect(&mut self, datagram: CapturedDatagram) -> InspectedPacket {
+        let mut buffer = datagram.data.clone();
+        let mut inspected = InspectedPacket {
+            datagram,
+            packet: None,
+            rendezvous: None,
+            from_initiator: None,
+            retransmission: false,
+            transition: None,
+            messages: Vec::new(),
+            error: None,
+        };
+
+        if let Err(error) = self.read(&mut buffer, &mut inspected) {
+            inspected.error = Some(error);
+        }
+        inspected
+    }
+
+    fn read(&mut self, datagram: &mut [u8], inspected: &mut InspectedPacket) -> Result<(), String> {
+        let kind = *datagram.first().ok_or("empty datagram")?;
+        if RendezvousMessage::is_rendezvous(kind) {
+            let message =
+                RendezvousMessage::decode(datagram).ok_or("malformed rendezvous message")?;
+            inspected.rendezvous = Some(message);
+            return Ok(());
+        }
+
+        let mut packet = Packet::decode(datagram, Instant::now()).ok_or("not a dserve packet")?;
+        let CapturedDatagram {
+            timestamp,
+            from,
+            to,
+            ..
+        } = inspected.datagram;
+
+        let keys = &self.keys;
+        let connection = self
+            .connections
+            .entry(packet.connection_id)
+            .or_insert_with(|| {
+                InspectedConnection::new(keys.get(&packet.connection_id), timestamp)
+            });
+        // Timestamps can go backwards within a capture
+        connection.first_seen = connection.first_seen.min(timestamp);
+        connection.last_seen = connection.last_seen.max(timestamp);
+        connection.packets += 1;
+        connection.bytes += datagram.len() as u64;
+
+        let (header, body) = datagram.split_at_mut(HEADER_SIZE);
+        match packet.kind {
+            PacketKind::Connect => {
+                packet.data = body.to_vec().into();
+                inspected.packet = Some(packet);
+                inspected.from_initiator = Some(true);
+                connection.initiator.get_or_insert(from);
+
+                if connection.state == ProtocolState::Idle {
+                    connection.state = ProtocolState::Connecting;
+                    inspected.transition = Some(ProtocolState::Connecting);
+                } else {
+                    inspected.retransmission = true;
+                    connection.retransmissions += 1;
+                }
+                Ok(())
+            }
+            PacketKind::Accept => {
+                packet.data = body.to_vec().into();
+                inspected.packet = Some(packet);
+                inspected.from_initiator = Some(false);
+                connection.initiator.get_or_insert(to);
+
+                if connection.state == ProtocolState::Connected {
+                    inspected.retransmission = true;
+                    connection.retransmissions += 1;
+                    return Ok(());
+                }
+
+                let chosen = body.get(PUBLIC_KEY_LEN..).unwrap_or_default();
+                let negotiated = self
+                    .compression
+                    .accept(chosen)
+                    .ok_or("accept is too short")?;
+                let dictionary = u32::from_be_bytes(chosen[2..6].try_into().unwrap());
+
+                connection.start(negotiated);
+                inspected.transition = Some(ProtocolState::Connected);
+
+                if dictionary != 0 && self.compression.dictionary_id() != dictionary {
+                    return Err(format!(
+                        "the peers use dictionary {}, which wasn't given",
+                        dictionary
+                    ));
+                }
+                Ok(())
+            }
+            _ => {
+                let (from_initiator, body) = match connection.open(&packet, header, body, from) {
+                    Ok(opened) => opened,
+                    Err(error) => {
+                        connection.decrypt_failures += 1;
+                        inspected.packet = Some(packet);
+                        return Err(error);
+                    }
+                };
+                packet.data = body.into();
+                inspected.packet = Some(packet.clone());
+                inspected.from_initiator = Some(from_initiator);
+
+                // The capture started after the handshake
+                if connection.state != ProtocolState::Connected {
+                    connection.state = ProtocolState::Connected;
+                    inspected.transition = Some(ProtocolState::Connected);
+                }
+
+                connection.receive(packet, from_initiator, &self.limits, inspected)
+            }
+        }
+    }
+}
+
+impl InspectedConnection {
+    pub fn new(key: Option<&[u8; 32]>, now: Duration) -> Self {
+        let openers = key.and_then(|key| {
+            Some([
+                EncryptionManager::with_key(key, true).ok()?,
+                EncryptionManager::with_key(key, false).ok()?,
+            ])
+        });
+
+        Self {
+            initiator: None,
+            state: ProtocolState::Idle,
+            openers,
+            negotiated: NegotiatedCompression::default(),
+            streams: [None, None],
+            messages: [HashSet::new(), HashSet::new()],
+            first_seen: now,
+            last_seen: now,
+            packets: 0,
+            bytes: 0,
+            retransmissions: 0,
+            decrypt_failures: 0,
+        }
+    }
+
+    fn start(&mut self, negotiated: NegotiatedCompression) {
+        if negotiated.streaming {
+            self.streams = [
+                Some(CompressionStream::new(STREAM_LEVEL)),
+                Some(CompressionStream::new(STREAM_LEVEL)),
+            ];
+        }
+        self.negotiated = negotiated;
+        self.state = ProtocolState::Connected;
+    }
+
+    // Decrypts the body with each direction's nonce, starting with the one the sender's
+    // address suggests. Returns whether the initiator sent it, and the plaintext.
+    fn open(
+        &self,
+        packet: &Packet,
+        header: &[u8],
+        body: &[u8],
+        from: SocketAddr,
+    ) -> Result<(bool, Vec<u8>), String> {
+        let openers = self.openers.as_ref().ok_or("no key for this connection")?;
+        let guess = self.initiator.is_none_or(|initiator| initiator == from);
+
+        for from_initiator in [guess, !guess] {
+            let mut attempt = body.to_vec();
+            if let Ok(plaintext) = openers[from_initiator as usize].decrypt_in_place(
+                packet.sequence,
+                header,
+                &mut attempt,
+            ) {
+                let len = plaintext.len();
+                attempt.truncate(len);
+                return Ok((from_initiator, attempt));
+            }
+        }
+
+        Err("failed to decrypt".to_string())
+    }
+
+    // Finds the messages in a decrypted packet, as the receiving session would
+    fn receive(
+        &mut self,
+        packet: Packet,
+        from_initiator: bool,
+        limits: &MessageLimits,
+        inspected: &mut InspectedPacket,
+    ) -> Result<(), String> {
+        let direction = from_initiator as usize;
+
+        match packet.kind {
+            PacketKind::Reliable => {
+                if !self.messages[direction].insert(packet.message_id) {
+                    inspected.retransmission = true;
+                    self.retransmissions += 1;
+                    return Ok(());
+                }
+            }
+            PacketKind::Unreliable => {}
+            _ => return Ok(()),
+        }
+
+        if packet.streamed {
+            let stream = self.streams[direction]
+                .as_mut()
+                .ok_or("streamed without a compression stream")?;
+            let position =
+                CompressionStream::position(&packet.data).ok_or("streamed message is too short")?;
+            stream.pending.insert(position, packet);
+
+            while let Some(held) = stream.pending.remove(&stream.next_received) {
+                let mut message = Vec::new();
+                if let Err(error) =
+                    stream.decompress(&held.data, &mut message, limits.max_message_size)
+                {
+                    // Nothing later in this direction can be read either
+                    self.streams[direction] = None;
+                    return Err(format!("compression stream broke: {}", error));
+                }
+                inspected.messages.push(message);
+            }
+            return Ok(());
+        }
+
+        let codec = match (packet.compressed, self.negotiated.codec(packet.kind)) {
+            (false, _) => Codec::None,
+            (true, Codec::None) => {
+                return Err("compressed with a codec that wasn't negotiated".to_string())
+            }
+            (true, codec) => codec,
+        };
+
+        let mut message = Vec::new();
+        codec
+            .decompress(
+                &packet.data,
+                self.negotiated.dictionary_bytes(),
+                &mut message,
+                limits.max_message_size,
+            )
+            .map_err(|error| format!("failed to decompress: {}", error))?;
+        inspected.messages.push(message);
+        Ok(())
+    }
+}
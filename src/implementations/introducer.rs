@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use ring::hmac;
+use tracing::debug;
+
+use crate::{
+    definitions::{Introducer, RegisteredPeer, RelayOffer},
+    enums::RendezvousMessage,
+    implementations::relay_credential,
+};
+
+impl Introducer {
+    pub fn new(expiry: Duration) -> Self {
+        Self {
+            peers: BTreeMap::new(),
+            expiry,
+            relay: None,
+        }
+    }
+
+    // Offers this relay to every introduced pair, signing credentials with the relay's secret
+    pub fn with_relay(mut self, relay: SocketAddr, secret: &[u8]) -> Self {
+        self.relay = Some((relay, hmac::Key::new(hmac::HMAC_SHA256, secret)));
+        self
+    }
+
+    // Answers a registration or introduction request, queueing replies in `replies`
+    pub fn handle_message(
+        &mut self,
+        message: RendezvousMessage,
+        from: SocketAddr,
+        now: Instant,
+        replies: &mut Vec<(RendezvousMessage, SocketAddr)>,
+    ) {
+        let expiry = self.expiry;
+        self.peers
+            .retain(|_, peer| now.duration_since(peer.last_seen) < expiry);
+
+        match message {
+            RendezvousMessage::Register {
+                peer_id,
+                local_candidates,
+            } => {
+                if !self.peers.contains_key(&peer_id) {
+                    debug!(peer_id, %from, "peer registered");
+                }
+                self.peers.insert(
+                    peer_id,
+                    RegisteredPeer {
+                        public_addr: from,
+                        local_candidates,
+                        last_seen: now,
+                    },
+                );
+
+                replies.push((RendezvousMessage::Registered { public_addr: from }, from));
+            }
+            RendezvousMessage::Introduce { peer_id, target } => {
+                // Only a registered peer, asking from its registered address, is introduced
+                let Some(requester) = self
+                    .peers
+                    .get(&peer_id)
+                    .filter(|requester| requester.public_addr == from)
+                else {
+                    return;
+                };
+
+                let Some(target_peer) = self.peers.get(&target).filter(|_| target != peer_id)
+                else {
+                    debug!(peer_id, target, "introduction to an unknown peer");
+                    replies.push((RendezvousMessage::UnknownPeer { peer_id: target }, from));
+                    return;
+                };
+
+                // Public address first: it works across networks, the local ones only within one
+                let candidates = |peer: &RegisteredPeer| {
+                    let mut candidates = vec![peer.public_addr];
+                    candidates.extend(
+                        peer.local_candidates
+                            .iter()
+                            .filter(|addr| **addr != peer.public_addr),
+                    );
+                    candidates
+                };
+
+                debug!(peer_id, target, "introducing");
+
+                // Both sides get the same connection id, so the relay can pair them up, but
+                // each its own credential
+                let connection_id = rand::random::<u64>().max(1);
+                let relay = |initiator| {
+                    self.relay.as_ref().map(|(relay, secret)| RelayOffer {
+                        relay: *relay,
+                        connection_id,
+                        credential: relay_credential(secret, connection_id, initiator),
+                    })
+                };
+
+                replies.push((
+                    RendezvousMessage::Introduction {
+                        peer_id: target,
+                        candidates: candidates(target_peer),
+                        initiator: true,
+                        relay: relay(true),
+                    },
+                    from,
+                ));
+                replies.push((
+                    RendezvousMessage::Introduction {
+                        peer_id,
+                        candidates: candidates(requester),
+                        initiator: false,
+                        relay: relay(false),
+                    },
+                    target_peer.public_addr,
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for Introducer {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
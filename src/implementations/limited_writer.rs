@@ -0,0 +1,33 @@
+use std::io::{self, Write};
+
+use crate::definitions::LimitedWriter;
+
+impl<'a> LimitedWriter<'a> {
+    pub fn new(output: &'a mut Vec<u8>, limit: usize) -> Self {
+        Self {
+            output,
+            remaining: limit,
+        }
+    }
+
+    // What a write past the limit fails with
+    pub fn limit_exceeded() -> io::Error {
+        io::Error::new(io::ErrorKind::OutOfMemory, "message exceeds the size limit")
+    }
+}
+
+impl Write for LimitedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.remaining {
+            return Err(Self::limit_exceeded());
+        }
+
+        self.remaining -= buf.len();
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+use tracing_subscriber::EnvFilter;
+
+// Logs to stderr, filtered by RUST_LOG (e.g. `dserve=debug`) and `info` otherwise. Connections
+// log their lifecycle at `info`, their retransmissions and handshake steps at `debug`, and
+// every packet at `trace`, so a busy server stays readable by default.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::hash_map::Entry,
+    io,
+    net::SocketAddr,
+    sync::{mpsc, Mutex},
+    time::Duration,
+};
+
+use crate::definitions::{MemoryNetwork, MemoryTransport, Transport};
+
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Port 0 picks a free ephemeral port, like binding a real socket
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+
+        let local_addr = if addr.port() == 0 {
+            EPHEMERAL_PORTS
+                .map(|port| SocketAddr::new(addr.ip(), port))
+                .find(|candidate| !endpoints.contains_key(candidate))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrInUse))?
+        } else {
+            addr
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        match endpoints.entry(local_addr) {
+            Entry::Occupied(_) => return Err(io::Error::from(io::ErrorKind::AddrInUse)),
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+            }
+        }
+
+        Ok(MemoryTransport {
+            local_addr,
+            network: self.clone(),
+            receiver,
+            pending: Mutex::new(None),
+        })
+    }
+}
+
+impl Transport for MemoryTransport {
+    // Datagrams to unbound addresses vanish, as they would over UDP
+    fn send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        if let Some(endpoint) = self.network.endpoints.lock().unwrap().get(&to) {
+            let _ = endpoint.send((datagram.to_vec(), self.local_addr));
+        }
+
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let received = match self.pending.lock().unwrap().take() {
+            Some(received) => received,
+            None => self
+                .receiver
+                .try_recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?,
+        };
+
+        // Truncates like a UDP read into a short buffer
+        let (datagram, from) = received;
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+
+        Ok((size, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_some() {
+            return Ok(());
+        }
+
+        *pending = match timeout {
+            Some(timeout) => self.receiver.recv_timeout(timeout).ok(),
+            None => self.receiver.recv().ok(),
+        };
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Ok(mut endpoints) = self.network.endpoints.lock() {
+            endpoints.remove(&self.local_addr);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use crate::definitions::MessageLimits;
+
+// Far beyond what a datagram decompresses to in normal use, far below what hurts a server
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 256 * 1024,
+            max_pending_bytes: 1024 * 1024,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    definitions::{EndpointStats, Histogram, Metrics, ProtocolCore},
+    enums::ProtocolState,
+};
+
+// Seconds, from LAN play to a struggling mobile link
+const RTT_BUCKETS: [f64; 9] = [0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.15, 0.25, 0.5];
+// Seconds, around the 16ms a 60Hz tick has to fit in
+const TICK_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.002, 0.004, 0.008, 0.016, 0.032, 0.064];
+// Sampling per update would weight busy connections over quiet ones
+const RTT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Sessions that end are dropped within the same update, so these are the only states one is
+// ever seen in
+const STATES: [(ProtocolState, &str); 2] = [
+    (ProtocolState::Connecting, "connecting"),
+    (ProtocolState::Connected, "connected"),
+];
+// Enough for the request line and the headers a scraper sends
+const MAX_REQUEST_SIZE: usize = 4096;
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    // Prometheus buckets are cumulative, each counting everything at or below its bound
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}").unwrap();
+        }
+        cumulative += self.counts[self.bounds.len()];
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}").unwrap();
+        writeln!(out, "{name}_sum {}", self.sum).unwrap();
+        writeln!(out, "{name}_count {cumulative}").unwrap();
+    }
+}
+
+fn render_value(
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+    out: &mut String,
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+impl Metrics {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            endpoint: EndpointStats::default(),
+            states: [0; 2],
+            rtt: Histogram::new(&RTT_BUCKETS),
+            next_sample: now,
+            tick_duration: None,
+            players: None,
+        }
+    }
+
+    // Takes the core's current totals and session states
+    pub fn record(&mut self, core: &ProtocolCore, now: Instant) {
+        self.endpoint = core.endpoint_stats();
+
+        self.states = STATES.map(|(state, _)| {
+            core.sessions
+                .values()
+                .filter(|session| session.state == state)
+                .count()
+        });
+
+        if now >= self.next_sample {
+            self.next_sample = now + RTT_SAMPLE_INTERVAL;
+            for session in core.sessions.values() {
+                if session.state == ProtocolState::Connected {
+                    self.rtt.observe(session.congestion.rtt.as_secs_f64());
+                }
+            }
+        }
+    }
+
+    pub fn observe_tick(&mut self, duration: Duration) {
+        self.tick_duration
+            .get_or_insert_with(|| Histogram::new(&TICK_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    // The Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let stats = &self.endpoint;
+
+        render_value(
+            "dserve_connections",
+            "gauge",
+            "Open connections.",
+            stats.connections,
+            &mut out,
+        );
+
+        writeln!(
+            out,
+            "# HELP dserve_connections_by_state Connections in each protocol state."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE dserve_connections_by_state gauge").unwrap();
+        for ((_, label), count) in STATES.iter().zip(self.states) {
+            writeln!(
+                out,
+                "dserve_connections_by_state{{state=\"{label}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        let counters = [
+            (
+                "dserve_bytes_sent_total",
+                "Bytes sent, headers included.",
+                stats.bytes_sent,
+            ),
+            (
+                "dserve_bytes_received_total",
+                "Bytes received, headers included.",
+                stats.bytes_received,
+            ),
+            (
+                "dserve_packets_sent_total",
+                "Packets sent.",
+                stats.packets_sent,
+            ),
+            (
+                "dserve_packets_received_total",
+                "Packets received.",
+                stats.packets_received,
+            ),
+            (
+                "dserve_retransmissions_total",
+                "Reliable messages sent again.",
+                stats.retransmissions,
+            ),
+            (
+                "dserve_packets_lost_total",
+                "Sent packets that were never acked.",
+                stats.packets_lost,
+            ),
+            (
+                "dserve_decrypt_failures_total",
+                "Datagrams that failed authentication.",
+                stats.decrypt_failures,
+            ),
+            (
+                "dserve_duplicates_total",
+                "Packets and messages received more than once.",
+                stats.duplicates,
+            ),
+        ];
+        for (name, help, value) in counters {
+            render_value(name, "counter", help, value, &mut out);
+        }
+
+        self.rtt.render(
+            "dserve_rtt_seconds",
+            "Smoothed round-trip time of each connection, sampled every second.",
+            &mut out,
+        );
+
+        if let Some(tick_duration) = &self.tick_duration {
+            tick_duration.render(
+                "dserve_tick_duration_seconds",
+                "Time taken by each server tick.",
+                &mut out,
+            );
+        }
+        if let Some(players) = self.players {
+            render_value(
+                "dserve_players",
+                "gauge",
+                "Players in the game.",
+                players,
+                &mut out,
+            );
+        }
+
+        out
+    }
+
+    // Answers `GET /metrics` on `addr` from a background thread, one request at a time
+    pub fn serve<A: ToSocketAddrs>(
+        metrics: Arc<Mutex<Self>>,
+        addr: A,
+    ) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+
+                // A scraper that hangs up early is no concern of ours
+                let _ = respond(stream, &metrics);
+            }
+        }))
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let size = stream.read(&mut buf)?;
+        if size == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..size]);
+    }
+
+    let found = request.starts_with(b"GET /metrics ") || request.starts_with(b"GET /metrics?");
+    let (status, body) = if found {
+        ("200 OK", metrics.lock().unwrap().render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
@@ -1,4 +1,40 @@
+#[cfg(feature = "tokio")]
+mod async_endpoint;
+mod buffer_pool;
+mod clock;
+mod codec;
+mod compression_config;
+mod compression_dictionary;
+mod compression_stream;
 mod congestion_control;
 mod encryption_manager;
+mod endpoint_stats;
+mod inspector;
+mod introducer;
+mod limited_writer;
+mod logging;
+mod memory_transport;
+mod message_limits;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod negotiated_compression;
+mod network_conditioner;
 mod network_protocol;
+mod offload_socket;
+mod packet;
 mod packet_buffer;
+mod packet_capture;
+mod protocol_core;
+mod relay;
+mod rendezvous_message;
+mod session;
+mod simulation;
+mod socket_config;
+mod udp_transport;
+
+pub use compression_dictionary::{read_samples, write_sample};
+pub use logging::init_logging;
+pub use packet::HEADER_SIZE;
+pub use packet_capture::read_keylog;
+pub use relay::relay_credential;
+pub use socket_config::bind_socket;
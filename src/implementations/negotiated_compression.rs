@@ -0,0 +1,34 @@
+use crate::{
+    definitions::NegotiatedCompression,
+    enums::{Codec, PacketKind},
+};
+
+impl NegotiatedCompression {
+    // Codec ids, dictionary id and the streaming flag
+    pub const ENCODED_LEN: usize = 7;
+
+    // Appended to an Accept
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend([self.reliable.id(), self.unreliable.id()]);
+        let dictionary = self
+            .dictionary
+            .as_ref()
+            .map_or(0, |dictionary| dictionary.id);
+        out.extend_from_slice(&dictionary.to_be_bytes());
+        out.push(self.streaming as u8);
+    }
+
+    pub fn codec(&self, kind: PacketKind) -> Codec {
+        match kind {
+            PacketKind::Reliable => self.reliable,
+            PacketKind::Unreliable => self.unreliable,
+            _ => Codec::None,
+        }
+    }
+
+    pub fn dictionary_bytes(&self) -> Option<&[u8]> {
+        self.dictionary
+            .as_ref()
+            .map(|dictionary| dictionary.bytes.as_slice())
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    io::{self, BufRead},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::definitions::{
+    ConditionedTransport, ConditionerState, DelayedDatagram, NetworkConditions, Transport,
+};
+
+const CONSOLE_HELP: &str = "network conditioner commands: on | off | latency <ms> | jitter <ms> | \
+loss <p> | burst <enter p> <exit p> <loss p> | duplicate <p> | reorder <p> <ms> | \
+bandwidth <bytes per second|off> | show";
+
+impl NetworkConditions {
+    // Applies one console command, e.g. `latency 80` or `burst 0.01 0.3 0.5`
+    pub fn apply_command(&mut self, line: &str) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        fn probability(word: Option<&&str>) -> Result<f64, String> {
+            let value: f64 = word
+                .ok_or("missing probability")?
+                .parse()
+                .map_err(|_| "invalid probability")?;
+
+            if !(0.0..=1.0).contains(&value) {
+                return Err("probability must be between 0 and 1".to_string());
+            }
+            Ok(value)
+        }
+
+        fn millis(word: Option<&&str>) -> Result<Duration, String> {
+            word.ok_or("missing milliseconds")?
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| "invalid milliseconds".to_string())
+        }
+
+        match words.first().copied() {
+            Some("on") => self.enabled = true,
+            Some("off") => self.enabled = false,
+            Some("latency") => self.latency = millis(words.get(1))?,
+            Some("jitter") => self.jitter = millis(words.get(1))?,
+            Some("loss") => self.loss = probability(words.get(1))?,
+            Some("burst") => {
+                self.burst_enter = probability(words.get(1))?;
+                self.burst_exit = probability(words.get(2))?;
+                self.burst_loss = probability(words.get(3))?;
+            }
+            Some("duplicate") => self.duplicate = probability(words.get(1))?,
+            Some("reorder") => {
+                self.reorder = probability(words.get(1))?;
+                self.reorder_delay = millis(words.get(2))?;
+            }
+            Some("bandwidth") => {
+                self.bandwidth = match words.get(1).copied() {
+                    Some("off") => None,
+                    Some(word) => Some(word.parse().map_err(|_| "invalid bandwidth")?),
+                    None => return Err("missing bandwidth".to_string()),
+                }
+            }
+            Some("show") => {}
+            _ => return Err(CONSOLE_HELP.to_string()),
+        }
+
+        Ok(())
+    }
+
+    // Reads commands from stdin so the example binaries can degrade the link while running
+    pub fn spawn_console(conditions: Arc<Mutex<Self>>) -> thread::JoinHandle<()> {
+        println!("{}", CONSOLE_HELP);
+
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+
+                let mut conditions = conditions.lock().unwrap();
+                match conditions.apply_command(&line) {
+                    Ok(()) => println!("{:?}", *conditions),
+                    Err(e) => println!("{}", e),
+                }
+            }
+        })
+    }
+}
+
+impl ConditionerState {
+    // The same seed and traffic always produce the same impairments
+    pub fn new(seed: u64, now: Instant) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            bursting: false,
+            link_free_at: now,
+            next_order: 0,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    // Decides the fate of an outgoing datagram, queueing whatever survives
+    pub fn submit(
+        &mut self,
+        conditions: &NetworkConditions,
+        datagram: &[u8],
+        to: SocketAddr,
+        now: Instant,
+    ) {
+        if !conditions.enabled {
+            self.push(datagram, to, now);
+            return;
+        }
+
+        // Gilbert-Elliott: step the two-state chain, then roll against the state's loss rate
+        let switch = self.rng.random::<f64>();
+        if self.bursting {
+            self.bursting = switch >= conditions.burst_exit;
+        } else {
+            self.bursting = switch < conditions.burst_enter;
+        }
+
+        let loss = if self.bursting {
+            conditions.burst_loss
+        } else {
+            conditions.loss
+        };
+
+        if self.rng.random::<f64>() < loss {
+            return;
+        }
+
+        self.schedule(conditions, datagram, to, now);
+        if self.rng.random::<f64>() < conditions.duplicate {
+            self.schedule(conditions, datagram, to, now);
+        }
+    }
+
+    fn schedule(
+        &mut self,
+        conditions: &NetworkConditions,
+        datagram: &[u8],
+        to: SocketAddr,
+        now: Instant,
+    ) {
+        // Datagrams queue behind each other on a capped link
+        let mut departs_at = now;
+        if let Some(bandwidth) = conditions.bandwidth.filter(|bandwidth| *bandwidth > 0) {
+            let transmission = Duration::from_secs_f64(datagram.len() as f64 / bandwidth as f64);
+            self.link_free_at = self.link_free_at.max(now) + transmission;
+            departs_at = self.link_free_at;
+        }
+
+        let mut delay = conditions.latency;
+        if !conditions.jitter.is_zero() {
+            delay += conditions.jitter.mul_f64(self.rng.random::<f64>());
+        }
+        if self.rng.random::<f64>() < conditions.reorder {
+            delay += conditions.reorder_delay;
+        }
+
+        self.push(datagram, to, departs_at + delay);
+    }
+
+    fn push(&mut self, datagram: &[u8], to: SocketAddr, release_at: Instant) {
+        let order = self.next_order;
+        self.next_order += 1;
+
+        self.queue.push(Reverse(DelayedDatagram {
+            release_at,
+            order,
+            datagram: datagram.to_vec(),
+            to,
+        }));
+    }
+
+    pub fn next_release(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(delayed)| delayed.release_at)
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Option<DelayedDatagram> {
+        if self.next_release()? > now {
+            return None;
+        }
+
+        self.queue.pop().map(|Reverse(delayed)| delayed)
+    }
+}
+
+impl<T: Transport> ConditionedTransport<T> {
+    pub fn new(inner: T, conditions: Arc<Mutex<NetworkConditions>>, seed: u64) -> Self {
+        Self {
+            inner,
+            conditions,
+            state: Mutex::new(ConditionerState::new(seed, Instant::now())),
+        }
+    }
+
+    pub fn conditions(&self) -> Arc<Mutex<NetworkConditions>> {
+        self.conditions.clone()
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.conditions.lock().unwrap().enabled = enabled;
+    }
+
+    // Datagrams still in flight are dropped
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    // Hands every datagram whose delay has passed to the inner transport
+    fn release(&self, now: Instant) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        while let Some(delayed) = state.pop_due(now) {
+            self.inner.send_to(&delayed.datagram, delayed.to)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for ConditionedTransport<T> {
+    // Lost datagrams still look sent to the caller, as they would over UDP
+    fn send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let now = Instant::now();
+        let conditions = self.conditions.lock().unwrap().clone();
+
+        self.state
+            .lock()
+            .unwrap()
+            .submit(&conditions, datagram, to, now);
+
+        // Zero-delay datagrams go straight out
+        self.release(now)?;
+        Ok(datagram.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.release(Instant::now())?;
+        self.inner.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    // Wakes early when a delayed datagram is due, since nothing else would send it
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let now = Instant::now();
+        self.release(now)?;
+
+        let next_release = self.state.lock().unwrap().next_release();
+        let timeout = match next_release {
+            Some(release_at) => {
+                let until_release = release_at.saturating_duration_since(now);
+                Some(timeout.map_or(until_release, |timeout| timeout.min(until_release)))
+            }
+            None => timeout,
+        };
+
+        // A zero read timeout is rejected by sockets
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Ok(());
+        }
+
+        self.inner.wait(timeout)?;
+        self.release(Instant::now())
+    }
+}
+
+impl PartialEq for DelayedDatagram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DelayedDatagram {}
+
+impl PartialOrd for DelayedDatagram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedDatagram {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.release_at, self.order).cmp(&(other.release_at, other.order))
+    }
+}
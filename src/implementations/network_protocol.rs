@@ -1,191 +1,398 @@
 use std::{
-    collections::HashMap,
-    io::Write,
-    net::UdpSocket,
-    time::{Duration, Instant},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    ops::Range,
+    path::Path,
+    time::Instant,
 };
 
-use flate2::{
-    write::{ZlibDecoder, ZlibEncoder},
-    Compression,
+#[cfg(feature = "keylog")]
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
 };
 
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+
+use tracing::{debug, warn};
+
 use crate::{
-    definitions::{CongestionControl, EncryptionManager, NetworkProtocol, Packet, PacketBuffer},
-    enums::ProtocolState,
+    definitions::{
+        Clock, CompressionConfig, ConnectionStats, EndpointStats, Introducer, MessageLimits,
+        NetworkProtocol, Packet, PacketCapture, ProtocolCore, Relay, SocketConfig, SystemClock,
+        Transport,
+    },
+    enums::{DisconnectReason, ProtocolEvent, ProtocolState},
+    implementations::bind_socket,
 };
 
+// Largest datagram read from the transport
+const MAX_DATAGRAM_SIZE: usize = 2048;
+// Datagrams read per `recv_batch` call
+const RECV_BATCH: usize = 32;
+
+// Send and receive errors caused by one peer or a full buffer, rather than by the socket
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if matches!(e.raw_os_error(), Some(libc::ENOBUFS | libc::EMSGSIZE)) {
+        return true;
+    }
+
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::PermissionDenied
+    )
+}
+
+// A capture that can't be written is given up on, rather than taking the endpoint down with it
+fn check_capture(capture: &mut Option<PacketCapture>, result: io::Result<()>) {
+    if let Err(e) = result {
+        warn!("stopped packet capture: {}", e);
+        *capture = None;
+    }
+}
+
 impl NetworkProtocol {
-    pub fn new(addr: &str) -> std::io::Result<Self> {
-        let socket = UdpSocket::bind(addr)?;
-        socket.set_nonblocking(true)?;
-
-        Ok(Self {
-            socket,
-            state: ProtocolState::Idle,
-            buffer: PacketBuffer::new(1024),
-            sequence_number: 0,
-            ack_number: 0,
-            congestion: CongestionControl::new(),
-            encryption: EncryptionManager::new(),
-            reliable_packets: HashMap::new(),
-            timeout: Duration::from_secs(5),
-        })
-    }
-
-    pub fn connect(&mut self, remote_addr: &str) -> std::io::Result<()> {
-        self.socket.connect(remote_addr)?;
-        self.state = ProtocolState::Connecting;
-
-        let connect_packet = Packet {
-            sequence: self.sequence_number,
-            data: vec![0x01],
-            timestamp: Instant::now(),
-            ack: 0,
-            ack_bits: 0,
-            attempts: 0,
-        };
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Self::with_transport(bind_socket(addr)?))
+    }
 
-        self.buffer.push_outgoing(connect_packet);
-        self.sequence_number += 1;
-        Ok(())
+    pub fn with_config<A: ToSocketAddrs>(addr: A, config: &SocketConfig) -> io::Result<Self> {
+        Ok(Self::with_transport(config.bind(addr)?))
     }
+}
 
-    pub fn update_state(&mut self) {
-        match self.state {
-            ProtocolState::Connecting => {
-                if let Some(packet) = self.buffer.incoming.front() {
-                    if packet.data[0] == 0x02 {
-                        self.state = ProtocolState::Connected;
-                    }
-                }
+impl<T: Transport> NetworkProtocol<T> {
+    pub fn with_transport(transport: T) -> Self {
+        Self::with_clock(transport, SystemClock)
+    }
+}
 
-                if let Some(packet) = self.buffer.outgoing.front() {
-                    if packet.timestamp.elapsed() > self.timeout {
-                        self.state = ProtocolState::Idle;
-                    }
-                }
-            }
-            ProtocolState::Connected => {
-                // Todo: Handle normal protocol operation
-            }
-            _ => {}
+impl<T: Transport, C: Clock> NetworkProtocol<T, C> {
+    pub fn with_clock(transport: T, clock: C) -> Self {
+        Self {
+            transport,
+            core: ProtocolCore::new(),
+            clock,
+            recv_buffers: vec![vec![0u8; MAX_DATAGRAM_SIZE]; RECV_BATCH],
+            received: Vec::with_capacity(RECV_BATCH),
+            send_queue: Vec::new(),
+            capture: None,
+            #[cfg(feature = "keylog")]
+            keylog: None,
         }
     }
 
-    pub fn send_reliable(&mut self, data: Vec<u8>) -> std::io::Result<()> {
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(&data)?;
-        let compressed = encoder.finish()?;
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    // Records every datagram sent or received from now on, replacing any earlier capture
+    pub fn capture_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.capture = Some(PacketCapture::create(path, self.transport.local_addr()?)?);
+        Ok(())
+    }
+
+    // Writes the keys of connections made from now on, which decrypt their captured traffic.
+    // Anyone holding the file can read those connections, so keep it to development.
+    #[cfg(feature = "keylog")]
+    pub fn keylog_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.keylog = Some(BufWriter::new(File::create(path)?));
+        self.core.export_keys = true;
+        Ok(())
+    }
 
-        let encrypted = self.encryption.encrypt(&compressed);
+    pub fn connect<A: ToSocketAddrs>(&mut self, remote_addr: A) -> io::Result<SocketAddr> {
+        let peer = self.resolve(remote_addr)?;
+        let peer = self.core.connect(peer, self.clock.now());
+        self.send_transmits()?;
 
-        let packet = Packet {
-            sequence: self.sequence_number,
-            ack: self.ack_number,
-            ack_bits: self.generate_ack_bits(),
-            data: encrypted,
-            timestamp: Instant::now(),
-            attempts: 0,
-        };
+        Ok(peer)
+    }
 
-        self.reliable_packets
-            .insert(self.sequence_number, packet.clone());
-        self.buffer.push_outgoing(packet);
-        self.sequence_number += 1;
+    // Like `connect`, offering these codecs instead of those set with `set_compression`
+    pub fn connect_with<A: ToSocketAddrs>(
+        &mut self,
+        remote_addr: A,
+        compression: CompressionConfig,
+    ) -> io::Result<SocketAddr> {
+        let peer = self.resolve(remote_addr)?;
+        let peer = self.core.connect_with(peer, compression, self.clock.now());
+        self.send_transmits()?;
+
+        Ok(peer)
+    }
 
-        Ok(())
+    // Codecs offered by connections from now on, in both directions
+    pub fn set_compression(&mut self, compression: CompressionConfig) {
+        self.core.compression = compression;
     }
 
-    pub fn generate_ack_bits(&self) -> u32 {
-        let mut ack_bits = 0u32;
+    // Limits applied to connections from now on
+    pub fn set_limits(&mut self, limits: MessageLimits) {
+        self.core.limits = limits;
+    }
 
-        for i in 1..=32 {
-            let seq = self.ack_number.wrapping_sub(i);
-            if self.reliable_packets.contains_key(&seq) {
-                ack_bits |= 1 << (i - 1);
-            }
-        }
+    pub fn disconnect(&mut self, peer: SocketAddr, reason: DisconnectReason) {
+        self.core.disconnect(peer, reason);
+    }
+
+    // None once the connection has closed
+    pub fn stats(&self, peer: SocketAddr) -> Option<ConnectionStats> {
+        self.core.stats(peer)
+    }
 
-        ack_bits
+    pub fn endpoint_stats(&self) -> EndpointStats {
+        self.core.endpoint_stats()
     }
 
-    pub fn handle_ack(&mut self, ack: u32, ack_bits: u32) {
-        self.reliable_packets.remove(&ack);
-        self.congestion.on_ack();
+    // Registers with an introducer as `peer_id`, offering our local address as well for
+    // peers on the same network
+    pub fn register<A: ToSocketAddrs>(&mut self, introducer: A, peer_id: u64) -> io::Result<()> {
+        let introducer = self.resolve(introducer)?;
+        let local_candidates = self.local_candidates(introducer)?;
 
-        for i in 1..=32 {
-            if (ack_bits & (1 << (i - 1))) != 0 {
-                let seq = ack.wrapping_sub(i);
-                self.reliable_packets.remove(&seq);
-            }
-        }
+        self.core
+            .register(introducer, peer_id, local_candidates, self.clock.now());
+        self.send_transmits()
     }
 
-    pub fn update(&mut self) -> std::io::Result<()> {
-        let now = Instant::now();
-        let mut retransmit = Vec::new();
+    // Punches through to another registered peer; watch for `PunchSucceeded`/`PunchFailed`
+    pub fn punch(&mut self, peer_id: u64) -> io::Result<()> {
+        self.core.punch(peer_id, self.clock.now())?;
+        self.send_transmits()
+    }
 
-        for (seq, packet) in self.reliable_packets.iter_mut() {
-            if now.duration_since(packet.timestamp) > self.congestion.rtt * 2 {
-                if packet.attempts < 5 {
-                    packet.attempts += 1;
-                    packet.timestamp = now;
-                    retransmit.push(packet.clone());
-                    self.congestion.on_loss();
-                } else {
-                    self.state = ProtocolState::Disconnecting;
-                }
-            }
+    pub fn enable_introducer(&mut self, introducer: Introducer) {
+        self.core.enable_introducer(introducer);
+    }
+
+    pub fn enable_relay(&mut self, relay: Relay) {
+        self.core.enable_relay(relay);
+    }
+
+    // An IPv4 socket can't reach IPv6 peers, while a dual-stack one reaches both
+    fn resolve<A: ToSocketAddrs>(&self, addr: A) -> io::Result<SocketAddr> {
+        let local_is_ipv6 = self.transport.local_addr()?.is_ipv6();
+
+        addr.to_socket_addrs()?
+            .find(|addr| local_is_ipv6 || addr.is_ipv4())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "could not resolve remote address",
+                )
+            })
+    }
+
+    // A wildcard bind doesn't say which address others see, so ask the routing table which
+    // one reaches the introducer. Connecting a UDP socket sends nothing.
+    fn local_candidates(&self, introducer: SocketAddr) -> io::Result<Vec<SocketAddr>> {
+        let local_addr = self.transport.local_addr()?;
+        if !local_addr.ip().is_unspecified() {
+            return Ok(vec![local_addr]);
         }
 
-        for packet in retransmit {
-            self.buffer.push_outgoing(packet);
+        let probe = UdpSocket::bind(if introducer.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+
+        Ok(
+            match probe.connect(introducer).and_then(|_| probe.local_addr()) {
+                Ok(route) => vec![SocketAddr::new(route.ip(), local_addr.port())],
+                Err(_) => Vec::new(),
+            },
+        )
+    }
+
+    pub fn state(&self, peer: SocketAddr) -> ProtocolState {
+        self.core.state(peer)
+    }
+
+    pub fn send_reliable(&mut self, peer: SocketAddr, data: Vec<u8>) -> io::Result<u32> {
+        self.core.send_reliable(peer, data, self.clock.now())
+    }
+
+    pub fn send_unreliable(&mut self, peer: SocketAddr, data: Vec<u8>) -> io::Result<u32> {
+        self.core.send_unreliable(peer, data, self.clock.now())
+    }
+
+    pub fn poll_message(&mut self) -> Option<(SocketAddr, Packet)> {
+        self.core.poll_message()
+    }
+
+    pub fn poll_message_from(&mut self, peer: SocketAddr) -> Option<Packet> {
+        self.core.poll_message_from(peer)
+    }
+
+    pub fn poll_event(&mut self) -> Option<ProtocolEvent> {
+        self.core.poll_event()
+    }
+
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.core.next_wakeup()
+    }
+
+    // The datagram is decrypted in place
+    pub fn handle_datagram(&mut self, datagram: &mut [u8], from: SocketAddr) -> io::Result<()> {
+        if let Some(capture) = self.capture.as_mut() {
+            let result = capture.record_received(datagram, from);
+            check_capture(&mut self.capture, result);
         }
 
+        self.core.handle_datagram(datagram, from, self.clock.now());
+        self.send_transmits()
+    }
+
+    // Runs due timers (retransmission, handshake retries, keepalive) and flushes the queue
+    pub fn handle_timeout(&mut self) -> io::Result<()> {
+        self.core.handle_timeout(self.clock.now());
+        self.send_transmits()
+    }
+
+    // Drains every datagram waiting on the transport
+    pub fn handle_readable(&mut self) -> io::Result<()> {
         loop {
-            let mut buf = [0u8; 2048];
-            match self.socket.recv_from(&mut buf) {
-                Ok((size, _)) => {
-                    let mut encrypted_data = buf[..size].to_vec();
-
-                    if let Ok(decrypted) = self.encryption.decrypt(&mut encrypted_data) {
-                        let mut decoder = ZlibDecoder::new(Vec::new());
-                        decoder.write_all(&decrypted)?;
-
-                        let decompressed = decoder.finish()?;
-
-                        let packet = Packet {
-                            sequence: self.sequence_number,
-                            ack: self.ack_number,
-                            ack_bits: 0,
-                            data: decompressed,
-                            timestamp: Instant::now(),
-                            attempts: 0,
-                        };
-
-                        self.buffer.push_incoming(packet.clone());
-                        self.handle_ack(packet.ack, packet.ack_bits);
+            match self
+                .transport
+                .recv_batch(&mut self.recv_buffers, &mut self.received)
+            {
+                Ok(()) => {
+                    let now = self.clock.now();
+                    for (buf, (size, from)) in self.recv_buffers.iter_mut().zip(&self.received) {
+                        if let Some(capture) = self.capture.as_mut() {
+                            let result = capture.record_received(&buf[..*size], *from);
+                            check_capture(&mut self.capture, result);
+                        }
+
+                        self.core.handle_datagram(&mut buf[..*size], *from, now);
                     }
                 }
-
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // An ICMP error for an earlier send, reported on some platforms
+                Err(ref e) if is_transient(e) => continue,
                 Err(e) => return Err(e),
             }
         }
 
+        self.core.flush(self.clock.now());
+        self.send_transmits()
+    }
+
+    fn send_transmits(&mut self) -> io::Result<()> {
+        while let Some(transmit) = self.core.poll_transmit() {
+            self.send_queue.push(transmit);
+        }
+
+        // A failed send is about the first datagram of the batch, which is dropped as the
+        // network might have and left to the reliability layer
         let mut sent = 0;
-        while let Some(packet) = self.buffer.outgoing.pop_front() {
-            if sent >= self.congestion.window_size {
-                break;
+        let mut result = Ok(());
+        while sent < self.send_queue.len() {
+            match self.transport.send_batch(&self.send_queue[sent..]) {
+                Ok(count) => {
+                    self.record_sent(sent..sent + count);
+                    sent += count;
+                }
+                // The socket buffer is full, so the rest would fail too
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    debug!(
+                        "socket buffer full, dropped {} datagrams",
+                        self.send_queue.len() - sent
+                    );
+                    break;
+                }
+                Err(e) if is_transient(&e) => {
+                    debug!(to = %self.send_queue[sent].1, "dropped datagram: {}", e);
+                    sent += 1;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
             }
+        }
+
+        #[cfg(feature = "keylog")]
+        self.write_keylog();
 
-            self.socket.send(&packet.data)?;
-            sent += 1;
+        for (datagram, _) in self.send_queue.drain(..) {
+            self.core.pool.put(datagram);
         }
 
-        self.update_state();
+        result
+    }
 
-        Ok(())
+    fn record_sent(&mut self, range: Range<usize>) {
+        if let Some(capture) = self.capture.as_mut() {
+            let recorded = self.send_queue[range]
+                .iter()
+                .try_for_each(|(datagram, to)| capture.record_sent(datagram, *to))
+                .and_then(|()| capture.flush());
+            check_capture(&mut self.capture, recorded);
+        }
+    }
+
+    #[cfg(feature = "keylog")]
+    fn write_keylog(&mut self) {
+        let Some(keylog) = self.keylog.as_mut() else {
+            self.core.exported_keys.clear();
+            return;
+        };
+
+        let mut result = Ok(());
+        while let Some((connection_id, key)) = self.core.exported_keys.pop_front() {
+            let key: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+            result = result.and_then(|()| writeln!(keylog, "{:016x} {}", connection_id, key));
+        }
+
+        if let Err(e) = result.and_then(|()| keylog.flush()) {
+            warn!("stopped writing keylog: {}", e);
+            self.keylog = None;
+        }
+    }
+
+    // Blocks until a datagram arrives or `deadline` passes, for loops without a poller
+    pub fn wait(&self, deadline: Option<Instant>) -> io::Result<()> {
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(self.clock.now()) {
+                Some(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => return Ok(()),
+            },
+            None => None,
+        };
+
+        self.transport.wait(timeout)
+    }
+
+    pub fn update(&mut self) -> io::Result<()> {
+        self.handle_timeout()?;
+        self.handle_readable()
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for NetworkProtocol {
+    fn as_raw_fd(&self) -> RawFd {
+        self.transport.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for NetworkProtocol {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.transport.as_raw_socket()
     }
 }
@@ -0,0 +1,348 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use std::{mem, os::fd::AsRawFd, ptr};
+
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
+
+use crate::{
+    definitions::{OffloadSocket, SocketConfig, Transport},
+    implementations::udp_transport::destination,
+};
+
+// Largest coalesced read: one full-size UDP payload
+const GRO_BUFFER_SIZE: usize = 65535;
+// The kernel's cap on segments per send (UDP_MAX_SEGMENTS)
+#[cfg(target_os = "linux")]
+const MAX_SEGMENTS: usize = 64;
+// Keeps a segmented send under the 64 KiB IP limit once headers are added
+#[cfg(target_os = "linux")]
+const MAX_SEGMENTED_PAYLOAD: usize = 65000;
+
+impl OffloadSocket {
+    // Probes the kernel and turns on whichever offloads it supports
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        let socket_is_ipv6 = socket.local_addr()?.is_ipv6();
+        let gso = gso_supported(&socket);
+        let gro = enable_gro(&socket);
+
+        Ok(Self {
+            socket,
+            socket_is_ipv6,
+            gso: AtomicBool::new(gso),
+            gro,
+            pending: Mutex::new(VecDeque::new()),
+            recv_buffer: Mutex::new(if gro {
+                vec![0u8; GRO_BUFFER_SIZE]
+            } else {
+                Vec::new()
+            }),
+            send_buffer: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: &SocketConfig) -> io::Result<Self> {
+        Self::new(config.bind(addr)?)
+    }
+
+    pub fn supports_gso(&self) -> bool {
+        self.gso.load(Ordering::Relaxed)
+    }
+
+    pub fn supports_gro(&self) -> bool {
+        self.gro
+    }
+
+    // How many datagrams from the front can go out as one segmented send: same destination,
+    // same size, except that the last one may be shorter
+    #[cfg(target_os = "linux")]
+    fn segment_run(datagrams: &[(Vec<u8>, SocketAddr)]) -> usize {
+        let Some((first, to)) = datagrams.first() else {
+            return 0;
+        };
+
+        let segment_size = first.len();
+        if segment_size == 0 {
+            return 1;
+        }
+
+        let mut total = segment_size;
+        let mut count = 1;
+        for (datagram, next_to) in &datagrams[1..] {
+            if next_to != to
+                || datagram.len() > segment_size
+                || datagram.is_empty()
+                || count == MAX_SEGMENTS
+                || total + datagram.len() > MAX_SEGMENTED_PAYLOAD
+            {
+                break;
+            }
+
+            total += datagram.len();
+            count += 1;
+
+            if datagram.len() < segment_size {
+                break;
+            }
+        }
+
+        count
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_segmented(&self, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<()> {
+        let segment_size = datagrams[0].0.len();
+        let to = SockAddr::from(destination(datagrams[0].1, self.socket_is_ipv6));
+
+        let mut buffer = self.send_buffer.lock().unwrap();
+        buffer.clear();
+        for (datagram, _) in datagrams {
+            buffer.extend_from_slice(datagram);
+        }
+
+        let mut iovec = libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        };
+
+        // u64s keep the control buffer aligned for cmsghdr
+        let mut control = [0u64; 8];
+        // Safety: all-zero is a valid msghdr
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = to.as_ptr() as *mut libc::c_void;
+        header.msg_namelen = to.len();
+        header.msg_iov = &mut iovec;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr().cast();
+        header.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+
+        // Safety: the control buffer has room for one u16 message
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segment_size as u16);
+        }
+
+        if unsafe { libc::sendmsg(self.socket.as_raw_fd(), &header, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // Reads one (possibly coalesced) datagram and queues its segments
+    #[cfg(target_os = "linux")]
+    fn recv_coalesced(&self) -> io::Result<()> {
+        let mut buffer = self.recv_buffer.lock().unwrap();
+
+        let mut iovec = libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        };
+
+        // Safety: all-zero is a valid value for these plain C structs
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        let mut control = [0u64; 8];
+        header.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast();
+        header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+        header.msg_iov = &mut iovec;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr().cast();
+        header.msg_controllen = mem::size_of_val(&control) as _;
+
+        let size =
+            unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut header, libc::MSG_DONTWAIT) };
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let size = size as usize;
+
+        // Safety: the kernel wrote a socket address of `msg_namelen` bytes
+        let from = unsafe { SockAddr::new(addr, header.msg_namelen) }
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP address"))?;
+
+        // Without a UDP_GRO message the read is a single datagram
+        let mut segment_size = size;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    segment_size =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>()) as usize;
+                }
+                cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+            }
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        for segment in buffer[..size].chunks(segment_size.max(1)) {
+            pending.push_back((segment.to_vec(), from));
+        }
+        if size == 0 {
+            pending.push_back((Vec::new(), from));
+        }
+
+        Ok(())
+    }
+
+    fn pop_pending(&self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let (datagram, from) = self.pending.lock().unwrap().pop_front()?;
+
+        // Truncates like a UDP read into a short buffer
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Some((size, from))
+    }
+}
+
+impl Transport for OffloadSocket {
+    fn send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.socket
+            .send_to(datagram, destination(to, self.socket_is_ipv6))
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if let Some(received) = self.pop_pending(buf) {
+            return Ok(received);
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.gro {
+            self.recv_coalesced()?;
+            return self
+                .pop_pending(buf)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        self.socket.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if !self.pending.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+
+        Transport::wait(&self.socket, timeout)
+    }
+
+    fn recv_batch(
+        &self,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        if !self.gro {
+            return self.socket.recv_batch(bufs, received);
+        }
+
+        received.clear();
+        loop {
+            while received.len() < bufs.len() {
+                let Some(datagram) = self.pop_pending(&mut bufs[received.len()]) else {
+                    break;
+                };
+                received.push(datagram);
+            }
+
+            if received.len() == bufs.len() {
+                return Ok(());
+            }
+
+            match self.recv_from(&mut bufs[received.len()]) {
+                Ok(datagram) => received.push(datagram),
+                Err(_) if !received.is_empty() => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send_batch(&self, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+        #[cfg(target_os = "linux")]
+        if self.supports_gso() {
+            let segments = Self::segment_run(datagrams);
+            if segments < 2 {
+                // Everything up to the next run worth segmenting goes out through sendmmsg
+                let singles = (1..datagrams.len())
+                    .find(|&at| Self::segment_run(&datagrams[at..]) >= 2)
+                    .unwrap_or(datagrams.len());
+                return self.socket.send_batch(&datagrams[..singles]);
+            }
+
+            match self.send_segmented(&datagrams[..segments]) {
+                Ok(()) => return Ok(segments),
+                // The kernel or device can't segment after all
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::EIO | libc::EINVAL | libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+                    ) =>
+                {
+                    self.gso.store(false, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.socket.send_batch(datagrams)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn gso_supported(socket: &UdpSocket) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    result == 0
+}
+
+#[cfg(target_os = "linux")]
+fn enable_gro(socket: &UdpSocket) -> bool {
+    let value: libc::c_int = 1;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    result == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn gso_supported(_socket: &UdpSocket) -> bool {
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_gro(_socket: &UdpSocket) -> bool {
+    false
+}
@@ -0,0 +1,74 @@
+use std::time::Instant;
+
+use crate::{
+    definitions::Packet,
+    enums::{PacketKind, Payload},
+};
+
+// kind, connection_id, sequence, ack, ack_bits, message_id
+pub const HEADER_SIZE: usize = 25;
+// Set in the kind byte of a message whose payload is compressed
+const COMPRESSED: u8 = 0x80;
+// Set in the kind byte of a message sent through the compression stream
+const STREAMED: u8 = 0x40;
+
+impl Packet {
+    pub fn new<D: Into<Payload>>(kind: PacketKind, message_id: u32, data: D, now: Instant) -> Self {
+        Self {
+            kind,
+            connection_id: 0,
+            sequence: 0,
+            ack: 0,
+            ack_bits: 0,
+            message_id,
+            compressed: false,
+            streamed: false,
+            data: data.into(),
+            timestamp: now,
+            attempts: 0,
+        }
+    }
+
+    pub fn encode_header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0] = self.kind as u8
+            | if self.compressed { COMPRESSED } else { 0 }
+            | if self.streamed { STREAMED } else { 0 };
+        header[1..9].copy_from_slice(&self.connection_id.to_be_bytes());
+        header[9..13].copy_from_slice(&self.sequence.to_be_bytes());
+        header[13..17].copy_from_slice(&self.ack.to_be_bytes());
+        header[17..21].copy_from_slice(&self.ack_bits.to_be_bytes());
+        header[21..25].copy_from_slice(&self.message_id.to_be_bytes());
+        header
+    }
+
+    // Parses the header only. The body stays in the datagram, where it is decrypted in place.
+    pub fn decode(datagram: &[u8], now: Instant) -> Option<Self> {
+        if datagram.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let field = |at: usize| u32::from_be_bytes(datagram[at..at + 4].try_into().unwrap());
+
+        Some(Self {
+            kind: PacketKind::from_u8(datagram[0] & !(COMPRESSED | STREAMED))?,
+            connection_id: Self::connection_id(datagram)?,
+            sequence: field(9),
+            ack: field(13),
+            ack_bits: field(17),
+            message_id: field(21),
+            compressed: datagram[0] & COMPRESSED != 0,
+            streamed: datagram[0] & STREAMED != 0,
+            data: Payload::default(),
+            timestamp: now,
+            attempts: 0,
+        })
+    }
+
+    // Reads just the connection id, for routing a datagram before its session is known
+    pub fn connection_id(datagram: &[u8]) -> Option<u64> {
+        datagram
+            .get(1..9)
+            .map(|id| u64::from_be_bytes(id.try_into().unwrap()))
+    }
+}
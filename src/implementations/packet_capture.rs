@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    definitions::{CapturedDatagram, PacketCapture},
+    implementations::protocol_core::canonical,
+};
+
+// Classic pcap, microsecond timestamps, written little-endian
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+// The same with nanosecond timestamps, which some tools write
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_MAGIC: u32 = 0x0a0d_0d0a;
+const PCAP_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+// Packets start at the IP header, whose version tells v4 from v6
+const LINKTYPE_RAW: u32 = 101;
+// What tcpdump writes for loopback and ordinary interfaces
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const SNAPLEN: u32 = 65535;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+const TTL: u8 = 64;
+
+impl PacketCapture {
+    // Starts a new capture file, replacing any at `path`
+    pub fn create<P: AsRef<Path>>(path: P, local_addr: SocketAddr) -> io::Result<Self> {
+        let mut pcap = BufWriter::new(File::create(path)?);
+
+        pcap.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        pcap.write_all(&2u16.to_le_bytes())?;
+        pcap.write_all(&4u16.to_le_bytes())?;
+        // Timezone offset and timestamp accuracy, both always zero
+        pcap.write_all(&[0; 8])?;
+        pcap.write_all(&SNAPLEN.to_le_bytes())?;
+        pcap.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        Ok(Self { pcap, local_addr })
+    }
+
+    pub fn record_sent(&mut self, datagram: &[u8], to: SocketAddr) -> io::Result<()> {
+        let to = canonical(to);
+        let from = self.local_for(to);
+        self.record(datagram, from, to)
+    }
+
+    pub fn record_received(&mut self, datagram: &[u8], from: SocketAddr) -> io::Result<()> {
+        let from = canonical(from);
+        let to = self.local_for(from);
+        self.record(datagram, from, to)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.pcap.flush()
+    }
+
+    // Reads the UDP datagrams in a pcap file, either one of ours or one from tcpdump. Other
+    // traffic, IP fragments included, is skipped, as is a record cut off at the end.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<CapturedDatagram>> {
+        let contents = fs::read(path)?;
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let header = contents
+            .get(..PCAP_HEADER_LEN)
+            .ok_or_else(|| invalid("capture is too short"))?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let (little_endian, nanos) = match (magic, magic.swap_bytes()) {
+            (PCAP_MAGIC, _) => (true, false),
+            (PCAP_MAGIC_NANOS, _) => (true, true),
+            (_, PCAP_MAGIC) => (false, false),
+            (_, PCAP_MAGIC_NANOS) => (false, true),
+            (PCAPNG_MAGIC, _) => return Err(invalid("pcapng isn't supported, save as pcap")),
+            _ => return Err(invalid("not a pcap file")),
+        };
+        let field = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            if little_endian {
+                u32::from_le_bytes(bytes)
+            } else {
+                u32::from_be_bytes(bytes)
+            }
+        };
+
+        // The upper bits can describe a frame check sequence, which doesn't matter here
+        let linktype = field(&header[20..24]) & 0xffff;
+        if ![
+            LINKTYPE_RAW,
+            LINKTYPE_NULL,
+            LINKTYPE_ETHERNET,
+            LINKTYPE_LINUX_SLL,
+            LINKTYPE_IPV4,
+            LINKTYPE_IPV6,
+        ]
+        .contains(&linktype)
+        {
+            return Err(invalid("capture is of an unsupported link type"));
+        }
+
+        let mut datagrams = Vec::new();
+        let mut rest = &contents[PCAP_HEADER_LEN..];
+        while rest.len() >= RECORD_HEADER_LEN {
+            let seconds = Duration::from_secs(field(&rest[0..4]) as u64);
+            let fraction = field(&rest[4..8]) as u64;
+            let len = field(&rest[8..12]) as usize;
+            let Some(frame) = rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+                break;
+            };
+            rest = &rest[RECORD_HEADER_LEN + len..];
+
+            let timestamp = seconds
+                + if nanos {
+                    Duration::from_nanos(fraction)
+                } else {
+                    Duration::from_micros(fraction)
+                };
+            let Some((from, to, data)) = link_payload(linktype, frame).and_then(udp_datagram)
+            else {
+                continue;
+            };
+
+            datagrams.push(CapturedDatagram {
+                timestamp,
+                from,
+                to,
+                data: data.to_vec(),
+            });
+        }
+
+        Ok(datagrams)
+    }
+
+    // Our address as seen by `peer`. A dual-stack socket talks to IPv4 peers over IPv4, and
+    // both ends of a captured packet need the same IP version.
+    fn local_for(&self, peer: SocketAddr) -> SocketAddr {
+        let port = self.local_addr.port();
+
+        match (self.local_addr.ip(), peer.ip()) {
+            (IpAddr::V6(local), IpAddr::V4(_)) => match local.to_ipv4_mapped() {
+                Some(local) => SocketAddr::new(local.into(), port),
+                None => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            },
+            (IpAddr::V4(local), IpAddr::V6(_)) => {
+                SocketAddr::new(local.to_ipv6_mapped().into(), port)
+            }
+            (local, _) => SocketAddr::new(local, port),
+        }
+    }
+
+    fn record(&mut self, datagram: &[u8], from: SocketAddr, to: SocketAddr) -> io::Result<()> {
+        let packet = ip_packet(datagram, from, to);
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.pcap
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.pcap
+            .write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.pcap.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.pcap.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.pcap.write_all(&packet)
+    }
+}
+
+// The datagram behind an IP and a UDP header, checksums included
+fn ip_packet(datagram: &[u8], from: SocketAddr, to: SocketAddr) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + datagram.len();
+
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&from.port().to_be_bytes());
+    udp.extend_from_slice(&to.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(datagram);
+
+    // The UDP checksum covers a pseudo-header of the addresses, protocol and length
+    let mut pseudo = Vec::new();
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+            pseudo.extend_from_slice(&[0, UDP_PROTOCOL]);
+            pseudo.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        }
+        (source, destination) => {
+            pseudo.extend_from_slice(&ipv6_octets(source));
+            pseudo.extend_from_slice(&ipv6_octets(destination));
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, UDP_PROTOCOL]);
+        }
+    }
+    pseudo.extend_from_slice(&udp);
+    // Zero means "no checksum", so a sum of zero is sent as all ones
+    let udp_checksum = match checksum(&pseudo) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + udp_len);
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let total_len = (IPV4_HEADER_LEN + udp_len) as u16;
+
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            // Identification, then "don't fragment"
+            packet.extend_from_slice(&[0, 0, 0x40, 0]);
+            packet.extend_from_slice(&[TTL, UDP_PROTOCOL, 0, 0]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+
+            let header_checksum = checksum(&packet);
+            packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        }
+        (source, destination) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[UDP_PROTOCOL, TTL]);
+            packet.extend_from_slice(&ipv6_octets(source));
+            packet.extend_from_slice(&ipv6_octets(destination));
+        }
+    }
+    packet.extend_from_slice(&udp);
+
+    packet
+}
+
+// The IP packet in a link-layer frame
+fn link_payload(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    let (ethertype, payload) = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => return Some(frame),
+        // A host-order address family, which the IP version repeats
+        LINKTYPE_NULL => return frame.get(4..),
+        LINKTYPE_ETHERNET => (frame.get(12..14)?, frame.get(14..)?),
+        LINKTYPE_LINUX_SLL => (frame.get(14..16)?, frame.get(16..)?),
+        _ => return None,
+    };
+
+    match u16::from_be_bytes(ethertype.try_into().unwrap()) {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(payload),
+        _ => None,
+    }
+}
+
+// The addresses and payload of a UDP packet. IPv6 extension headers aren't followed.
+fn udp_datagram(ip: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let u16_at = |bytes: &[u8], at: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(
+            bytes.get(at..at + 2)?.try_into().unwrap(),
+        ))
+    };
+
+    let (source, destination, udp) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = u16_at(ip, 2)? as usize;
+            // Either a fragment or the first of several
+            let fragmented = u16_at(ip, 6)? & 0x3fff != 0;
+            if fragmented || *ip.get(9)? != UDP_PROTOCOL {
+                return None;
+            }
+
+            let source: [u8; 4] = ip.get(12..16)?.try_into().unwrap();
+            let destination: [u8; 4] = ip.get(16..20)?.try_into().unwrap();
+            let udp = ip.get(header_len..total_len.min(ip.len()))?;
+            (IpAddr::from(source), IpAddr::from(destination), udp)
+        }
+        6 => {
+            if *ip.get(6)? != UDP_PROTOCOL {
+                return None;
+            }
+
+            let payload_len = u16_at(ip, 4)? as usize;
+            let source: [u8; 16] = ip.get(8..24)?.try_into().unwrap();
+            let destination: [u8; 16] = ip.get(24..40)?.try_into().unwrap();
+            let udp = ip.get(IPV6_HEADER_LEN..(IPV6_HEADER_LEN + payload_len).min(ip.len()))?;
+            (IpAddr::from(source), IpAddr::from(destination), udp)
+        }
+        _ => return None,
+    };
+
+    // A truncated header, which `clamp` below can't take
+    if udp.len() < UDP_HEADER_LEN {
+        return None;
+    }
+
+    let udp_len = (u16_at(udp, 4)? as usize).clamp(UDP_HEADER_LEN, udp.len());
+    let from = canonical(SocketAddr::new(source, u16_at(udp, 0)?));
+    let to = canonical(SocketAddr::new(destination, u16_at(udp, 2)?));
+    Some((from, to, &udp[UDP_HEADER_LEN..udp_len]))
+}
+
+// The keys in a keylog by connection id, skipping lines that don't parse
+pub fn read_keylog<P: AsRef<Path>>(path: P) -> io::Result<HashMap<u64, [u8; 32]>> {
+    let contents = fs::read_to_string(path)?;
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let (id, key) = line.trim().split_once(' ')?;
+            let id = u64::from_str_radix(id, 16).ok()?;
+            if key.len() != 64 {
+                return None;
+            }
+
+            let mut bytes = [0u8; 32];
+            for (at, byte) in bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(key.get(at * 2..at * 2 + 2)?, 16).ok()?;
+            }
+            Some((id, bytes))
+        })
+        .collect())
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// The Internet checksum: the ones' complement of the ones' complement sum of 16-bit words
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
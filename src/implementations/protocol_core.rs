@@ -0,0 +1,785 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, trace};
+
+use crate::{
+    definitions::{
+        BufferPool, CompressionConfig, ConnectionStats, EndpointStats, HolePunch, Introducer,
+        MessageLimits, Packet, ProtocolCore, Relay, RelayOffer, RelayedPath, Rendezvous, Session,
+    },
+    enums::{
+        DeliveryEvent, DisconnectReason, PacketKind, ProtocolEvent, ProtocolState,
+        RendezvousMessage,
+    },
+};
+
+// Registration is retried quickly until acknowledged, then renewed well within the
+// 30 seconds or so after which NATs commonly drop an idle UDP mapping
+const REGISTER_RETRY: Duration = Duration::from_secs(1);
+const REGISTER_INTERVAL: Duration = Duration::from_secs(15);
+const INTRODUCE_RETRY: Duration = Duration::from_secs(1);
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+const ALLOCATE_RETRY: Duration = Duration::from_secs(1);
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+// Delivery events past this many undrained ones are only counted, for endpoints that never poll
+const MAX_QUEUED_DELIVERIES: usize = 4096;
+
+// A dual-stack socket reports IPv4 peers as `::ffff:a.b.c.d`, so sessions are keyed by the
+// plain IPv4 form whichever way the address arrived
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+impl ProtocolCore {
+    pub fn new() -> Self {
+        Self::with_pool(Arc::new(BufferPool::default()))
+    }
+
+    // Lets several cores, or a core and the application, recycle the same buffers
+    pub fn with_pool(pool: Arc<BufferPool>) -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+            connection_ids: BTreeMap::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+            unreported: BTreeMap::new(),
+            pool,
+            compression: CompressionConfig::default(),
+            limits: MessageLimits::default(),
+            #[cfg(feature = "keylog")]
+            export_keys: false,
+            #[cfg(feature = "keylog")]
+            exported_keys: VecDeque::new(),
+            closed_stats: EndpointStats::default(),
+            rendezvous: None,
+            punches: BTreeMap::new(),
+            introducer: None,
+            relays: BTreeMap::new(),
+            relay: None,
+        }
+    }
+
+    // Returns the address the session is keyed and reported by
+    pub fn connect(&mut self, peer: SocketAddr, now: Instant) -> SocketAddr {
+        self.connect_with(peer, self.compression.clone(), now)
+    }
+
+    // Like `connect`, offering these codecs instead of the core's
+    pub fn connect_with(
+        &mut self,
+        peer: SocketAddr,
+        compression: CompressionConfig,
+        now: Instant,
+    ) -> SocketAddr {
+        let peer = canonical(peer);
+        let mut session = self.new_session(peer, self.new_connection_id(), now);
+        session.compression = compression;
+        session.connect(now);
+
+        self.insert_session(session);
+        self.collect(peer, ProtocolState::Idle);
+        peer
+    }
+
+    fn new_session(&self, peer: SocketAddr, connection_id: u64, now: Instant) -> Session {
+        let mut session = Session::new(peer, connection_id, now, self.pool.clone());
+        session.compression = self.compression.clone();
+        session.limits = self.limits.clone();
+        #[cfg(feature = "keylog")]
+        {
+            session.encryption.export_key = self.export_keys;
+        }
+        session
+    }
+
+    // Replaces whatever session the peer's address had
+    fn insert_session(&mut self, session: Session) {
+        if let Some(replaced) = self.sessions.remove(&session.peer) {
+            self.connection_ids.remove(&replaced.connection_id);
+            self.closed_stats.add(&replaced.stats);
+        }
+        self.connection_ids
+            .insert(session.connection_id, session.peer);
+        self.sessions.insert(session.peer, session);
+    }
+
+    // Zero is left for "no connection"
+    fn new_connection_id(&self) -> u64 {
+        loop {
+            let connection_id = rand::random::<u64>();
+            if connection_id != 0 && !self.connection_ids.contains_key(&connection_id) {
+                return connection_id;
+            }
+        }
+    }
+
+    // Also serve registrations and introductions for other peers
+    pub fn enable_introducer(&mut self, introducer: Introducer) {
+        self.introducer = Some(introducer);
+    }
+
+    // Also forward datagrams for peers that were offered this relay
+    pub fn enable_relay(&mut self, relay: Relay) {
+        self.relay = Some(relay);
+    }
+
+    // Registers with an introducer as `peer_id`, so that other peers can punch through to us
+    pub fn register(
+        &mut self,
+        introducer: SocketAddr,
+        peer_id: u64,
+        local_candidates: Vec<SocketAddr>,
+        now: Instant,
+    ) {
+        self.rendezvous = Some(Rendezvous {
+            introducer: canonical(introducer),
+            peer_id,
+            local_candidates,
+            public_addr: None,
+            next_register: now,
+        });
+
+        self.send_register(now);
+    }
+
+    // Opens a path to a registered peer, reported with `PunchSucceeded` or `PunchFailed`. The
+    // introducer picks which side connects once the path works.
+    pub fn punch(&mut self, peer_id: u64, now: Instant) -> io::Result<()> {
+        if self.rendezvous.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "not registered with an introducer",
+            ));
+        }
+
+        self.punches.insert(
+            peer_id,
+            HolePunch {
+                peer_id,
+                candidates: Vec::new(),
+                initiator: true,
+                relay: None,
+                next_probe: now,
+                deadline: now + PUNCH_TIMEOUT,
+            },
+        );
+
+        self.probe(peer_id, now);
+        Ok(())
+    }
+
+    fn send_register(&mut self, now: Instant) {
+        let Some(rendezvous) = self.rendezvous.as_mut() else {
+            return;
+        };
+
+        rendezvous.next_register = now
+            + if rendezvous.public_addr.is_some() {
+                REGISTER_INTERVAL
+            } else {
+                REGISTER_RETRY
+            };
+
+        let message = RendezvousMessage::Register {
+            peer_id: rendezvous.peer_id,
+            local_candidates: rendezvous.local_candidates.clone(),
+        };
+        let introducer = rendezvous.introducer;
+        self.send_rendezvous(&message, introducer);
+    }
+
+    fn send_rendezvous(&mut self, message: &RendezvousMessage, to: SocketAddr) {
+        let mut datagram = self.pool.take();
+        message.encode(&mut datagram);
+        self.transmits.push_back((datagram, to));
+    }
+
+    // Asks for an introduction until one arrives, then sends a punch to every candidate.
+    // Our outgoing punches are what open our own NAT to the peer's.
+    fn probe(&mut self, peer_id: u64, now: Instant) {
+        let (Some(rendezvous), Some(punch)) =
+            (self.rendezvous.as_ref(), self.punches.get_mut(&peer_id))
+        else {
+            return;
+        };
+
+        let own_id = rendezvous.peer_id;
+        let introducer = rendezvous.introducer;
+
+        if punch.candidates.is_empty() {
+            punch.next_probe = now + INTRODUCE_RETRY;
+            let introduce = RendezvousMessage::Introduce {
+                peer_id: own_id,
+                target: peer_id,
+            };
+            return self.send_rendezvous(&introduce, introducer);
+        }
+
+        punch.next_probe = now + PUNCH_INTERVAL;
+        let candidates = punch.candidates.clone();
+        for candidate in candidates {
+            let message = RendezvousMessage::Punch {
+                from: own_id,
+                to: peer_id,
+            };
+            self.send_rendezvous(&message, candidate);
+        }
+    }
+
+    fn handle_rendezvous(&mut self, datagram: &[u8], from: SocketAddr, now: Instant) {
+        let Some(message) = RendezvousMessage::decode(datagram) else {
+            return;
+        };
+
+        // Introducer replies are only trusted from the introducer
+        let from_introducer = self
+            .rendezvous
+            .as_ref()
+            .is_some_and(|rendezvous| rendezvous.introducer == from);
+        let own_id = self
+            .rendezvous
+            .as_ref()
+            .map(|rendezvous| rendezvous.peer_id);
+
+        match message {
+            RendezvousMessage::Allocate { .. } => {
+                let Some(relay) = self.relay.as_mut() else {
+                    return;
+                };
+
+                let mut replies = Vec::new();
+                relay.handle_message(message, from, now, &mut replies);
+                for (reply, to) in replies {
+                    self.send_rendezvous(&reply, to);
+                }
+            }
+            RendezvousMessage::Register { .. } | RendezvousMessage::Introduce { .. } => {
+                let Some(introducer) = self.introducer.as_mut() else {
+                    return;
+                };
+
+                let mut replies = Vec::new();
+                introducer.handle_message(message, from, now, &mut replies);
+                for (reply, to) in replies {
+                    self.send_rendezvous(&reply, to);
+                }
+            }
+            RendezvousMessage::Registered { public_addr } if from_introducer => {
+                let public_addr = canonical(public_addr);
+                let Some(rendezvous) = self.rendezvous.as_mut() else {
+                    return;
+                };
+
+                rendezvous.next_register = now + REGISTER_INTERVAL;
+                if rendezvous.public_addr != Some(public_addr) {
+                    info!(%public_addr, "registered with introducer");
+                    rendezvous.public_addr = Some(public_addr);
+                    self.events
+                        .push_back(ProtocolEvent::Registered(public_addr));
+                }
+            }
+            // Also how the other side of a punch learns about it
+            RendezvousMessage::Introduction {
+                peer_id,
+                candidates,
+                initiator,
+                relay,
+            } if from_introducer => {
+                let punch = self.punches.entry(peer_id).or_insert_with(|| HolePunch {
+                    peer_id,
+                    candidates: Vec::new(),
+                    initiator,
+                    relay: None,
+                    next_probe: now,
+                    deadline: now + PUNCH_TIMEOUT,
+                });
+
+                debug!(peer_id, ?candidates, initiator, "introduced to peer");
+                punch.candidates = candidates.into_iter().map(canonical).collect();
+                punch.initiator = initiator;
+                punch.relay = relay.map(|offer| RelayOffer {
+                    relay: canonical(offer.relay),
+                    ..offer
+                });
+                self.probe(peer_id, now);
+            }
+            RendezvousMessage::UnknownPeer { peer_id } if from_introducer => {
+                self.fail_punch(peer_id);
+            }
+            // Answered even after our own punch has finished, since the peer may still be waiting
+            RendezvousMessage::Punch { from: peer_id, to } if Some(to) == own_id => {
+                let ack = RendezvousMessage::PunchAck {
+                    from: to,
+                    to: peer_id,
+                };
+                self.send_rendezvous(&ack, from);
+                self.punched(peer_id, from, now);
+            }
+            RendezvousMessage::PunchAck { from: peer_id, to } if Some(to) == own_id => {
+                self.punched(peer_id, from, now);
+            }
+            RendezvousMessage::Allocated { connection_id } => {
+                self.allocated(connection_id, from, now);
+            }
+            RendezvousMessage::Refused { connection_id } => {
+                let Some(path) = self.relays.get(&connection_id) else {
+                    return;
+                };
+
+                if path.offer.relay == from {
+                    let peer_id = path.peer_id;
+                    info!(peer_id, relay = %from, "relay refused allocation");
+                    self.relays.remove(&connection_id);
+                    self.events.push_back(ProtocolEvent::PunchFailed(peer_id));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // No direct path opened, so the connection goes through the relay the introducer offered
+    fn fall_back_to_relay(&mut self, peer_id: u64, now: Instant) {
+        let Some(punch) = self.punches.remove(&peer_id) else {
+            return;
+        };
+
+        let Some(offer) = punch.relay else {
+            info!(peer_id, "hole punch timed out");
+            self.events.push_back(ProtocolEvent::PunchFailed(peer_id));
+            return;
+        };
+
+        info!(peer_id, relay = %offer.relay, "hole punch timed out, falling back to relay");
+
+        let connection_id = offer.connection_id;
+        self.relays.insert(
+            connection_id,
+            RelayedPath {
+                peer_id,
+                peer: punch.candidates.first().copied().unwrap_or(offer.relay),
+                offer,
+                initiator: punch.initiator,
+                allocated: false,
+                next_allocate: now,
+                deadline: now + RELAY_TIMEOUT,
+            },
+        );
+
+        self.send_allocate(connection_id, now);
+    }
+
+    fn send_allocate(&mut self, connection_id: u64, now: Instant) {
+        let Some(path) = self.relays.get_mut(&connection_id) else {
+            return;
+        };
+
+        path.next_allocate = now + ALLOCATE_RETRY;
+        let message = RendezvousMessage::Allocate {
+            connection_id,
+            initiator: path.initiator,
+            credential: path.offer.credential,
+        };
+        let relay = path.offer.relay;
+        self.send_rendezvous(&message, relay);
+    }
+
+    // The relay will forward between us and the peer once both sides are allocated. The
+    // initiator connects through it; the other side waits for that handshake.
+    fn allocated(&mut self, connection_id: u64, from: SocketAddr, now: Instant) {
+        let Some(path) = self
+            .relays
+            .get_mut(&connection_id)
+            .filter(|path| path.offer.relay == from && !path.allocated)
+        else {
+            return;
+        };
+
+        path.allocated = true;
+        let (peer_id, peer, initiator) = (path.peer_id, path.peer, path.initiator);
+        info!(peer_id, %peer, relay = %from, "relay allocated");
+        self.events.push_back(ProtocolEvent::Relayed(peer_id, peer));
+
+        if initiator {
+            self.relays.remove(&connection_id);
+
+            let mut session = self.new_session(peer, connection_id, now);
+            session.relay = Some(from);
+            session.connect(now);
+
+            self.insert_session(session);
+            self.collect(peer, ProtocolState::Idle);
+        }
+    }
+
+    fn fail_punch(&mut self, peer_id: u64) {
+        if self.punches.remove(&peer_id).is_some() {
+            info!(peer_id, "introducer doesn't know the peer");
+            self.events.push_back(ProtocolEvent::PunchFailed(peer_id));
+        }
+    }
+
+    // A punch or its ack got through, so `addr` reaches the peer and it reaches us
+    fn punched(&mut self, peer_id: u64, addr: SocketAddr, now: Instant) {
+        let Some(punch) = self.punches.remove(&peer_id) else {
+            return;
+        };
+
+        info!(peer_id, %addr, "hole punch succeeded");
+        self.events
+            .push_back(ProtocolEvent::PunchSucceeded(peer_id, addr));
+
+        if punch.initiator && !self.sessions.contains_key(&addr) {
+            self.connect(addr, now);
+        }
+    }
+
+    pub fn state(&self, peer: SocketAddr) -> ProtocolState {
+        self.sessions
+            .get(&canonical(peer))
+            .map_or(ProtocolState::Idle, |session| session.state)
+    }
+
+    pub fn send_reliable(
+        &mut self,
+        peer: SocketAddr,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> io::Result<u32> {
+        self.session_mut(peer)?.send_reliable(data, now)
+    }
+
+    pub fn send_unreliable(
+        &mut self,
+        peer: SocketAddr,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> io::Result<u32> {
+        self.session_mut(peer)?.send_unreliable(data, now)
+    }
+
+    pub fn stats(&self, peer: SocketAddr) -> Option<ConnectionStats> {
+        self.sessions.get(&canonical(peer)).map(Session::stats)
+    }
+
+    // Totals over open and closed sessions alike
+    pub fn endpoint_stats(&self) -> EndpointStats {
+        let mut stats = self.closed_stats;
+        for session in self.sessions.values() {
+            stats.add(&session.stats);
+        }
+        stats.connections = self.sessions.len();
+        stats
+    }
+
+    // Drops the session with `reason`, e.g. for a peer that sent something the application
+    // couldn't parse
+    pub fn disconnect(&mut self, peer: SocketAddr, reason: DisconnectReason) {
+        let peer = canonical(peer);
+        let Some(session) = self.sessions.get_mut(&peer) else {
+            return;
+        };
+
+        let previous = session.state;
+        session.close(reason);
+        self.collect(peer, previous);
+    }
+
+    fn session_mut(&mut self, peer: SocketAddr) -> io::Result<&mut Session> {
+        self.sessions
+            .get_mut(&canonical(peer))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no session with this peer"))
+    }
+
+    // The datagram is decrypted in place
+    pub fn handle_datagram(&mut self, datagram: &mut [u8], from: SocketAddr, now: Instant) {
+        let from = canonical(from);
+        if datagram
+            .first()
+            .is_some_and(|kind| RendezvousMessage::is_rendezvous(*kind))
+        {
+            return self.handle_rendezvous(datagram, from, now);
+        }
+
+        let Some(connection_id) = Packet::connection_id(datagram) else {
+            return;
+        };
+
+        // Relayed datagrams are passed on sealed, whether or not the other side is there yet
+        if let Some(relay) = self
+            .relay
+            .as_mut()
+            .filter(|relay| relay.relays(connection_id))
+        {
+            if let Some(to) = relay.forward(connection_id, datagram.len(), from, now) {
+                let mut forwarded = self.pool.take();
+                forwarded.extend_from_slice(datagram);
+                self.transmits.push_back((forwarded, to));
+            }
+            return;
+        }
+
+        // The connection id finds a session even when its peer's address has changed
+        let peer = match self.connection_ids.get(&connection_id) {
+            Some(peer) => *peer,
+            // Unknown peers can only open a session with a handshake
+            None => {
+                if datagram.first() != Some(&(PacketKind::Connect as u8)) || connection_id == 0 {
+                    trace!(%from, "dropped a datagram for an unknown connection");
+                    return;
+                }
+
+                // A relayed handshake is keyed by the peer, not the relay it came through
+                let relayed = self
+                    .relays
+                    .get(&connection_id)
+                    .filter(|path| path.allocated && path.offer.relay == from);
+
+                let (peer, relay) = match relayed {
+                    Some(path) => (path.peer, Some(from)),
+                    None if self.sessions.contains_key(&from) => return,
+                    None => (from, None),
+                };
+
+                let mut session = self.new_session(peer, connection_id, now);
+                session.relay = relay;
+                session.handle_datagram(datagram, from, now);
+
+                // Only a valid handshake opens a session, so a bad one leaves nothing to close
+                if session.state != ProtocolState::Connected {
+                    return;
+                }
+                if relay.is_some() {
+                    self.relays.remove(&connection_id);
+                }
+                self.insert_session(session);
+                return self.collect(peer, ProtocolState::Idle);
+            }
+        };
+
+        let Some(session) = self.sessions.get_mut(&peer) else {
+            return;
+        };
+
+        let previous = session.state;
+        session.handle_datagram(datagram, from, now);
+
+        let current = session.peer;
+        if current != peer {
+            self.migrate(peer, current);
+        }
+        self.collect(current, previous);
+    }
+
+    // Re-keys a session whose peer has proved it moved. Whatever session the new address
+    // had is stale, since the address now belongs to this peer.
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        if let Some(stale) = self.sessions.remove(&to) {
+            self.connection_ids.remove(&stale.connection_id);
+            self.closed_stats.add(&stale.stats);
+            debug!(peer = %to, "replaced by a connection that moved to its address");
+            self.events
+                .push_back(ProtocolEvent::Disconnected(to, DisconnectReason::Replaced));
+        }
+
+        let Some(session) = self.sessions.remove(&from) else {
+            return;
+        };
+
+        self.connection_ids.insert(session.connection_id, to);
+        self.sessions.insert(to, session);
+        self.events.push_back(ProtocolEvent::Migrated(from, to));
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let peers: Vec<SocketAddr> = self.sessions.keys().copied().collect();
+
+        for peer in peers {
+            if let Some(session) = self.sessions.get_mut(&peer) {
+                let previous = session.state;
+                session.handle_timeout(now);
+                self.collect(peer, previous);
+            }
+        }
+
+        if self
+            .rendezvous
+            .as_ref()
+            .is_some_and(|rendezvous| now >= rendezvous.next_register)
+        {
+            self.send_register(now);
+        }
+
+        let due: Vec<(u64, bool)> = self
+            .punches
+            .values()
+            .filter(|punch| now >= punch.next_probe || now >= punch.deadline)
+            .map(|punch| (punch.peer_id, now >= punch.deadline))
+            .collect();
+
+        for (peer_id, expired) in due {
+            if expired {
+                self.fall_back_to_relay(peer_id, now);
+            } else {
+                self.probe(peer_id, now);
+            }
+        }
+
+        let due: Vec<(u64, bool)> = self
+            .relays
+            .iter()
+            .filter(|(_, path)| {
+                now >= path.deadline || (!path.allocated && now >= path.next_allocate)
+            })
+            .map(|(connection_id, path)| (*connection_id, now >= path.deadline))
+            .collect();
+
+        for (connection_id, expired) in due {
+            if !expired {
+                self.send_allocate(connection_id, now);
+                continue;
+            }
+
+            // Once allocated, the peer was told of the relay and the failure is its to report
+            if let Some(path) = self.relays.remove(&connection_id) {
+                if !path.allocated {
+                    info!(peer_id = path.peer_id, "relay never answered");
+                    self.events
+                        .push_back(ProtocolEvent::PunchFailed(path.peer_id));
+                }
+            }
+        }
+    }
+
+    // Sends whatever the sessions have queued, without running any timers
+    pub fn flush(&mut self, now: Instant) {
+        let peers: Vec<SocketAddr> = self.sessions.keys().copied().collect();
+
+        for peer in peers {
+            if let Some(session) = self.sessions.get_mut(&peer) {
+                let previous = session.state;
+                session.flush(now);
+                self.collect(peer, previous);
+            }
+        }
+    }
+
+    // None while nothing is scheduled, in which case only a datagram can create work
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        let sessions = self.sessions.values().map(Session::next_wakeup);
+        let punches = self
+            .punches
+            .values()
+            .map(|punch| punch.next_probe.min(punch.deadline));
+        let relays = self.relays.values().map(|path| {
+            if path.allocated {
+                path.deadline
+            } else {
+                path.next_allocate.min(path.deadline)
+            }
+        });
+        let register = self
+            .rendezvous
+            .as_ref()
+            .map(|rendezvous| rendezvous.next_register);
+
+        sessions.chain(punches).chain(relays).chain(register).min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ProtocolEvent> {
+        // Reported once the queue has room again, after the events that filled it
+        while self.events.len() < MAX_QUEUED_DELIVERIES {
+            let Some((peer, count)) = self.unreported.pop_first() else {
+                break;
+            };
+            self.events.push_back(ProtocolEvent::Delivery(
+                peer,
+                DeliveryEvent::Unreported(count),
+            ));
+        }
+
+        self.events.pop_front()
+    }
+
+    pub fn poll_message(&mut self) -> Option<(SocketAddr, Packet)> {
+        self.sessions.iter_mut().find_map(|(peer, session)| {
+            session
+                .buffer
+                .incoming
+                .pop_front()
+                .map(|packet| (*peer, packet))
+        })
+    }
+
+    pub fn poll_message_from(&mut self, peer: SocketAddr) -> Option<Packet> {
+        self.sessions
+            .get_mut(&canonical(peer))?
+            .buffer
+            .incoming
+            .pop_front()
+    }
+
+    // Moves a session's output into the core's queues and drops it once it has ended
+    fn collect(&mut self, peer: SocketAddr, previous: ProtocolState) {
+        let Some(session) = self.sessions.get_mut(&peer) else {
+            return;
+        };
+
+        while let Some(transmit) = session.transmits.pop_front() {
+            self.transmits.push_back(transmit);
+        }
+
+        while let Some(event) = session.events.pop_front() {
+            if self.events.len() < MAX_QUEUED_DELIVERIES {
+                self.events.push_back(ProtocolEvent::Delivery(peer, event));
+            } else {
+                *self.unreported.entry(peer).or_default() += 1;
+            }
+        }
+
+        #[cfg(feature = "keylog")]
+        if let Some(key) = session.encryption.exported_key.take() {
+            self.exported_keys.push_back((session.connection_id, key));
+        }
+
+        match session.state {
+            ProtocolState::Connected if previous != ProtocolState::Connected => {
+                self.events.push_back(ProtocolEvent::Connected(peer));
+            }
+            ProtocolState::Idle | ProtocolState::Disconnecting => {
+                let connection_id = session.connection_id;
+                let reason = session
+                    .disconnect_reason
+                    .unwrap_or(DisconnectReason::TimedOut);
+                self.closed_stats.add(&session.stats);
+                self.sessions.remove(&peer);
+                self.connection_ids.remove(&connection_id);
+                self.events
+                    .push_back(ProtocolEvent::Disconnected(peer, reason));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for ProtocolCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
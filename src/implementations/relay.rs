@@ -0,0 +1,185 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use ring::hmac;
+use tracing::{debug, trace};
+
+use crate::{
+    definitions::{Relay, RelayAllocation, RelayLimits},
+    enums::RendezvousMessage,
+};
+
+// Proves to a relay that whoever handed out `connection_id` knew its secret. Each side gets
+// its own, so one side's credential can't claim the other's slot.
+pub fn relay_credential(secret: &hmac::Key, connection_id: u64, initiator: bool) -> [u8; 32] {
+    hmac::sign(secret, &credential_input(connection_id, initiator))
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+fn credential_input(connection_id: u64, initiator: bool) -> [u8; 9] {
+    let mut input = [0; 9];
+    input[..8].copy_from_slice(&connection_id.to_be_bytes());
+    input[8] = initiator as u8;
+    input
+}
+
+// Tops up a token bucket for the time since it was last refilled
+fn refill(allowance: &mut f64, refilled_at: &mut Instant, rate: u64, burst: u64, now: Instant) {
+    let elapsed = now.saturating_duration_since(*refilled_at).as_secs_f64();
+    *allowance = (*allowance + elapsed * rate as f64).min(burst as f64);
+    *refilled_at = now;
+}
+
+impl Relay {
+    pub fn new(secret: &[u8], limits: RelayLimits, now: Instant) -> Self {
+        let allowance = limits.total_bytes_per_second.unwrap_or(0) as f64;
+
+        Self {
+            secret: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            limits,
+            allocations: BTreeMap::new(),
+            allowance,
+            refilled_at: now,
+        }
+    }
+
+    pub fn relays(&self, connection_id: u64) -> bool {
+        self.allocations.contains_key(&connection_id)
+    }
+
+    // Answers an allocation request, queueing the reply in `replies`
+    pub fn handle_message(
+        &mut self,
+        message: RendezvousMessage,
+        from: SocketAddr,
+        now: Instant,
+        replies: &mut Vec<(RendezvousMessage, SocketAddr)>,
+    ) {
+        let RendezvousMessage::Allocate {
+            connection_id,
+            initiator,
+            credential,
+        } = message
+        else {
+            return;
+        };
+
+        let idle_timeout = self.limits.idle_timeout;
+        self.allocations
+            .retain(|_, allocation| now.duration_since(allocation.last_active) < idle_timeout);
+
+        let valid = hmac::verify(
+            &self.secret,
+            &credential_input(connection_id, initiator),
+            &credential,
+        )
+        .is_ok();
+        let full = self.allocations.len() >= self.limits.max_allocations;
+        let side = initiator as usize;
+
+        // A side asking again from a new address is the same peer behind a rebound NAT
+        let accepted = valid
+            && match self.allocations.get_mut(&connection_id) {
+                Some(allocation) => {
+                    allocation.peers[side] = Some(from);
+                    allocation.last_active = now;
+                    true
+                }
+                None if !full => {
+                    let mut peers = [None; 2];
+                    peers[side] = Some(from);
+                    self.allocations.insert(
+                        connection_id,
+                        RelayAllocation {
+                            peers,
+                            allowance: self.limits.burst as f64,
+                            refilled_at: now,
+                            last_active: now,
+                            forwarded_bytes: 0,
+                            dropped_bytes: 0,
+                        },
+                    );
+                    true
+                }
+                None => false,
+            };
+
+        debug!(connection_id, %from, accepted, "allocation requested");
+        let reply = if accepted {
+            RendezvousMessage::Allocated { connection_id }
+        } else {
+            RendezvousMessage::Refused { connection_id }
+        };
+        replies.push((reply, from));
+    }
+
+    // Where to forward a datagram of a relayed connection, or None to drop it: it came from
+    // outside the allocation, the other side hasn't arrived yet, or a quota ran out
+    pub fn forward(
+        &mut self,
+        connection_id: u64,
+        size: usize,
+        from: SocketAddr,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        let limits = &self.limits;
+        let allocation = self.allocations.get_mut(&connection_id)?;
+
+        let to = match allocation.peers {
+            [Some(a), Some(b)] if a == from => b,
+            [Some(a), Some(b)] if b == from => a,
+            _ => return None,
+        };
+
+        refill(
+            &mut allocation.allowance,
+            &mut allocation.refilled_at,
+            limits.bytes_per_second,
+            limits.burst,
+            now,
+        );
+        if let Some(total) = limits.total_bytes_per_second {
+            refill(
+                &mut self.allowance,
+                &mut self.refilled_at,
+                total,
+                total,
+                now,
+            );
+        }
+
+        let size = size as f64;
+        let over_total = limits.total_bytes_per_second.is_some() && self.allowance < size;
+        if allocation.allowance < size || over_total {
+            allocation.dropped_bytes += size as u64;
+            trace!(connection_id, %from, "over quota, dropped");
+            return None;
+        }
+
+        allocation.allowance -= size;
+        if limits.total_bytes_per_second.is_some() {
+            self.allowance -= size;
+        }
+        allocation.forwarded_bytes += size as u64;
+        allocation.last_active = now;
+
+        Some(to)
+    }
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self {
+            bytes_per_second: 256 * 1024,
+            burst: 64 * 1024,
+            total_bytes_per_second: None,
+            max_allocations: 1024,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{definitions::RelayOffer, enums::RendezvousMessage};
+
+const REGISTER: u8 = 0x10;
+const REGISTERED: u8 = 0x11;
+const INTRODUCE: u8 = 0x12;
+const INTRODUCTION: u8 = 0x13;
+const UNKNOWN_PEER: u8 = 0x14;
+const PUNCH: u8 = 0x15;
+const PUNCH_ACK: u8 = 0x16;
+const ALLOCATE: u8 = 0x17;
+const ALLOCATED: u8 = 0x18;
+const REFUSED: u8 = 0x19;
+
+// Messages listing more candidates than this are dropped
+pub const MAX_CANDIDATES: usize = 8;
+
+impl RendezvousMessage {
+    // Whether a datagram starting with this byte is one of these messages
+    pub fn is_rendezvous(kind: u8) -> bool {
+        (REGISTER..=REFUSED).contains(&kind)
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Register {
+                peer_id,
+                local_candidates,
+            } => {
+                out.push(REGISTER);
+                out.extend_from_slice(&peer_id.to_be_bytes());
+                encode_addrs(local_candidates, out);
+            }
+            Self::Registered { public_addr } => {
+                out.push(REGISTERED);
+                encode_addr(*public_addr, out);
+            }
+            Self::Introduce { peer_id, target } => {
+                out.push(INTRODUCE);
+                out.extend_from_slice(&peer_id.to_be_bytes());
+                out.extend_from_slice(&target.to_be_bytes());
+            }
+            Self::Introduction {
+                peer_id,
+                candidates,
+                initiator,
+                relay,
+            } => {
+                out.push(INTRODUCTION);
+                out.extend_from_slice(&peer_id.to_be_bytes());
+                out.push(*initiator as u8);
+                encode_addrs(candidates, out);
+
+                match relay {
+                    Some(offer) => {
+                        out.push(1);
+                        encode_addr(offer.relay, out);
+                        out.extend_from_slice(&offer.connection_id.to_be_bytes());
+                        out.extend_from_slice(&offer.credential);
+                    }
+                    None => out.push(0),
+                }
+            }
+            Self::UnknownPeer { peer_id } => {
+                out.push(UNKNOWN_PEER);
+                out.extend_from_slice(&peer_id.to_be_bytes());
+            }
+            Self::Punch { from, to } => {
+                out.push(PUNCH);
+                out.extend_from_slice(&from.to_be_bytes());
+                out.extend_from_slice(&to.to_be_bytes());
+            }
+            Self::PunchAck { from, to } => {
+                out.push(PUNCH_ACK);
+                out.extend_from_slice(&from.to_be_bytes());
+                out.extend_from_slice(&to.to_be_bytes());
+            }
+            Self::Allocate {
+                connection_id,
+                initiator,
+                credential,
+            } => {
+                out.push(ALLOCATE);
+                out.extend_from_slice(&connection_id.to_be_bytes());
+                out.push(*initiator as u8);
+                out.extend_from_slice(credential);
+            }
+            Self::Allocated { connection_id } => {
+                out.push(ALLOCATED);
+                out.extend_from_slice(&connection_id.to_be_bytes());
+            }
+            Self::Refused { connection_id } => {
+                out.push(REFUSED);
+                out.extend_from_slice(&connection_id.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        let (&kind, mut rest) = datagram.split_first()?;
+        let rest = &mut rest;
+
+        let message = match kind {
+            REGISTER => Self::Register {
+                peer_id: read_u64(rest)?,
+                local_candidates: decode_addrs(rest)?,
+            },
+            REGISTERED => Self::Registered {
+                public_addr: decode_addr(rest)?,
+            },
+            INTRODUCE => Self::Introduce {
+                peer_id: read_u64(rest)?,
+                target: read_u64(rest)?,
+            },
+            INTRODUCTION => Self::Introduction {
+                peer_id: read_u64(rest)?,
+                initiator: read_u8(rest)? != 0,
+                candidates: decode_addrs(rest)?,
+                relay: match read_u8(rest)? {
+                    0 => None,
+                    _ => Some(RelayOffer {
+                        relay: decode_addr(rest)?,
+                        connection_id: read_u64(rest)?,
+                        credential: read_credential(rest)?,
+                    }),
+                },
+            },
+            UNKNOWN_PEER => Self::UnknownPeer {
+                peer_id: read_u64(rest)?,
+            },
+            PUNCH => Self::Punch {
+                from: read_u64(rest)?,
+                to: read_u64(rest)?,
+            },
+            PUNCH_ACK => Self::PunchAck {
+                from: read_u64(rest)?,
+                to: read_u64(rest)?,
+            },
+            ALLOCATE => Self::Allocate {
+                connection_id: read_u64(rest)?,
+                initiator: read_u8(rest)? != 0,
+                credential: read_credential(rest)?,
+            },
+            ALLOCATED => Self::Allocated {
+                connection_id: read_u64(rest)?,
+            },
+            REFUSED => Self::Refused {
+                connection_id: read_u64(rest)?,
+            },
+            _ => return None,
+        };
+
+        Some(message)
+    }
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Some(taken)
+}
+
+fn read_u8(rest: &mut &[u8]) -> Option<u8> {
+    take(rest, 1).map(|bytes| bytes[0])
+}
+
+fn read_u64(rest: &mut &[u8]) -> Option<u64> {
+    take(rest, 8).map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_credential(rest: &mut &[u8]) -> Option<[u8; 32]> {
+    take(rest, 32).map(|bytes| bytes.try_into().unwrap())
+}
+
+// Family (4 or 6), address bytes, port
+fn encode_addr(addr: SocketAddr, out: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn decode_addr(rest: &mut &[u8]) -> Option<SocketAddr> {
+    let ip = match read_u8(rest)? {
+        4 => IpAddr::from(<[u8; 4]>::try_from(take(rest, 4)?).unwrap()),
+        6 => IpAddr::from(<[u8; 16]>::try_from(take(rest, 16)?).unwrap()),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(take(rest, 2)?.try_into().unwrap());
+
+    Some(SocketAddr::new(ip, port))
+}
+
+fn encode_addrs(addrs: &[SocketAddr], out: &mut Vec<u8>) {
+    let count = addrs.len().min(MAX_CANDIDATES);
+    out.push(count as u8);
+    for addr in &addrs[..count] {
+        encode_addr(*addr, out);
+    }
+}
+
+fn decode_addrs(rest: &mut &[u8]) -> Option<Vec<SocketAddr>> {
+    let count = read_u8(rest)? as usize;
+    if count > MAX_CANDIDATES {
+        return None;
+    }
+
+    (0..count).map(|_| decode_addr(rest)).collect()
+}
@@ -0,0 +1,815 @@
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    definitions::{
+        BufferPool, CompressionConfig, CompressionStream, CongestionControl, ConnectionStats,
+        EncryptionManager, MessageLimits, NegotiatedCompression, Packet, PacketBuffer, SentPacket,
+        Session,
+    },
+    enums::{
+        Codec, DeliveryEvent, DisconnectReason, PacketKind, Payload, ProtocolState, Violation,
+    },
+    implementations::HEADER_SIZE,
+};
+
+// Acks only reach this many packets behind the most recent one
+const ACK_WINDOW: u32 = 32;
+const MAX_ATTEMPTS: u8 = 5;
+const RECEIVED_MESSAGE_HISTORY: u32 = 1024;
+// Spacing between flushes while packets wait on the congestion window
+const PACING_INTERVAL: Duration = Duration::from_millis(16);
+// X25519, at the start of every handshake packet
+const PUBLIC_KEY_LEN: usize = 32;
+
+fn sequence_greater_than(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+impl Session {
+    pub fn new(peer: SocketAddr, connection_id: u64, now: Instant, pool: Arc<BufferPool>) -> Self {
+        Self {
+            peer,
+            connection_id,
+            state: ProtocolState::Idle,
+            buffer: PacketBuffer::new(1024),
+            backlog: VecDeque::new(),
+            sequence_number: 0,
+            ack_number: 0,
+            ack_bits: 0,
+            ack_pending: false,
+            largest_received: 0,
+            path_challenge: None,
+            relay: None,
+            message_id: 0,
+            congestion: CongestionControl::new(now),
+            encryption: EncryptionManager::new(),
+            compression: CompressionConfig::default(),
+            negotiated: NegotiatedCompression::default(),
+            stream: None,
+            limits: MessageLimits::default(),
+            stats: ConnectionStats::default(),
+            disconnect_reason: None,
+            pool,
+            reliable_packets: BTreeMap::new(),
+            sent_packets: BTreeMap::new(),
+            received_messages: HashSet::new(),
+            events: VecDeque::new(),
+            transmits: VecDeque::new(),
+            last_activity: now,
+            last_send: now,
+            next_flush: now,
+            keepalive: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            span: tracing::info_span!("connection", peer = %peer, id = connection_id),
+        }
+    }
+
+    pub fn connect(&mut self, now: Instant) {
+        let _span = self.span.clone().entered();
+        debug!("connecting");
+
+        self.state = ProtocolState::Connecting;
+        self.last_activity = now;
+
+        self.send_handshake(PacketKind::Connect, now);
+    }
+
+    pub fn update_state(&mut self, now: Instant) {
+        let idle_for = now.duration_since(self.last_activity);
+
+        match self.state {
+            ProtocolState::Connecting if idle_for >= self.timeout => {
+                info!("handshake timed out");
+                self.state = ProtocolState::Idle;
+                self.disconnect_reason
+                    .get_or_insert(DisconnectReason::TimedOut);
+            }
+            // The peer has stopped sending, even keepalives
+            ProtocolState::Connected if idle_for >= self.timeout => {
+                self.close(DisconnectReason::TimedOut);
+            }
+            _ => {}
+        }
+    }
+
+    // The session's counters, with its current RTT, window and queues
+    pub fn stats(&self) -> ConnectionStats {
+        let sent = self.stats.packets_acked + self.stats.packets_lost;
+
+        ConnectionStats {
+            loss_rate: if sent == 0 {
+                0.0
+            } else {
+                self.stats.packets_lost as f64 / sent as f64
+            },
+            rtt: self.congestion.rtt,
+            rtt_var: self.congestion.rtt_var,
+            window_size: self.congestion.window_size,
+            incoming_queue: self.buffer.incoming.len(),
+            outgoing_queue: self.buffer.outgoing.len(),
+            unacked_messages: self.reliable_packets.len(),
+            ..self.stats
+        }
+    }
+
+    // Ends the session; the first reason given is the one reported
+    pub fn close(&mut self, reason: DisconnectReason) {
+        let _span = self.span.clone().entered();
+        self.state = ProtocolState::Disconnecting;
+
+        if self.disconnect_reason.is_none() {
+            match reason {
+                DisconnectReason::ProtocolViolation(_) => warn!(?reason, "disconnecting"),
+                _ => info!(?reason, "disconnecting"),
+            }
+            self.disconnect_reason = Some(reason);
+        }
+    }
+
+    // Drops a peer whose message couldn't be decompressed within the limits
+    fn violation(&mut self, error: io::Error) {
+        let violation = match error.kind() {
+            io::ErrorKind::OutOfMemory => Violation::MessageTooLarge,
+            _ => Violation::MalformedMessage,
+        };
+        self.close(DisconnectReason::ProtocolViolation(violation));
+    }
+
+    // Earliest instant at which `handle_timeout` has work to do
+    pub fn next_wakeup(&self) -> Instant {
+        let retransmit_after = self.congestion.retransmit_timeout();
+        let mut deadline = self.last_activity + self.timeout;
+
+        match self.state {
+            ProtocolState::Connecting => {
+                deadline = deadline.min(self.last_send + retransmit_after);
+            }
+            ProtocolState::Connected => {
+                // Owed since the packet that needs acking arrived
+                if self.ack_pending {
+                    return self.last_activity;
+                }
+
+                deadline = deadline.min(self.last_send + self.keepalive);
+
+                if !self.buffer.outgoing.is_empty() {
+                    deadline = deadline.min(self.next_flush);
+                }
+
+                for packet in self.reliable_packets.values() {
+                    if packet.attempts > 0 {
+                        deadline = deadline.min(packet.timestamp + retransmit_after);
+                    }
+                }
+
+                for sent in self.sent_packets.values() {
+                    deadline = deadline.min(sent.sent_at + self.timeout);
+                }
+            }
+            _ => {}
+        }
+
+        deadline
+    }
+
+    pub fn send_reliable(&mut self, data: Vec<u8>, now: Instant) -> io::Result<u32> {
+        self.ensure_open()?;
+
+        let message_id = self.next_message_id();
+        let packet = self.message(PacketKind::Reliable, message_id, data, now)?;
+
+        // Stays in `reliable_packets` until acked, so a full queue is retried later. The
+        // payload is shared rather than copied.
+        self.reliable_packets.insert(message_id, packet.clone());
+        if !self.backlog.is_empty() || !self.buffer.push_outgoing(packet) {
+            self.backlog.push_back(message_id);
+        }
+
+        Ok(message_id)
+    }
+
+    pub fn send_unreliable(&mut self, data: Vec<u8>, now: Instant) -> io::Result<u32> {
+        self.ensure_open()?;
+
+        let message_id = self.next_message_id();
+        let packet = self.message(PacketKind::Unreliable, message_id, data, now)?;
+
+        if !self.buffer.push_outgoing(packet) {
+            self.events.push_back(DeliveryEvent::Lost(message_id));
+        }
+
+        Ok(message_id)
+    }
+
+    // Compresses the payload unless it is too short, or compressing doesn't make it shorter.
+    // While streaming, every reliable message goes through the stream so that all of them are
+    // delivered in order.
+    fn message(
+        &mut self,
+        kind: PacketKind,
+        message_id: u32,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> io::Result<Packet> {
+        if let Some(stream) = self
+            .stream
+            .as_mut()
+            .filter(|_| kind == PacketKind::Reliable)
+        {
+            let mut streamed = Vec::new();
+            stream.compress(&data, &mut streamed)?;
+
+            let mut packet = Packet::new(kind, message_id, streamed, now);
+            packet.streamed = true;
+            return Ok(packet);
+        }
+
+        let codec = self.negotiated.codec(kind);
+        if codec == Codec::None || data.len() < self.compression.threshold {
+            return Ok(Packet::new(kind, message_id, data, now));
+        }
+
+        let mut compressed = Vec::new();
+        codec.compress(&data, self.negotiated.dictionary_bytes(), &mut compressed)?;
+        if compressed.len() >= data.len() {
+            return Ok(Packet::new(kind, message_id, data, now));
+        }
+
+        let mut packet = Packet::new(kind, message_id, compressed, now);
+        packet.compressed = true;
+        Ok(packet)
+    }
+
+    // Both sides start a fresh stream with each connection, so a reconnect never resumes a
+    // stream the other side has lost
+    fn start_compression(&mut self, negotiated: NegotiatedCompression) {
+        self.stream = negotiated
+            .streaming
+            .then(|| CompressionStream::new(self.compression.stream_level()));
+        self.negotiated = negotiated;
+    }
+
+    fn ensure_open(&self) -> io::Result<()> {
+        match self.state {
+            ProtocolState::Connecting | ProtocolState::Connected => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "protocol is not connected",
+            )),
+        }
+    }
+
+    fn next_message_id(&mut self) -> u32 {
+        let message_id = self.message_id;
+        self.message_id = self.message_id.wrapping_add(1);
+        message_id
+    }
+
+    // Streamed messages waiting on earlier ones count too, as they all go into the queue
+    fn has_room(&self) -> bool {
+        let pending = self
+            .stream
+            .as_ref()
+            .map_or(0, |stream| stream.pending.len());
+        self.buffer.incoming.len() + pending < self.buffer.max_size
+    }
+
+    // Records a received sequence in the ack history, returning false for duplicates
+    fn record_received(&mut self, sequence: u32) -> bool {
+        if sequence_greater_than(sequence, self.ack_number) {
+            let shift = sequence.wrapping_sub(self.ack_number);
+            self.ack_bits = if shift > ACK_WINDOW {
+                0
+            } else {
+                (((self.ack_bits as u64) << shift) | (1 << (shift - 1))) as u32
+            };
+            self.ack_number = sequence;
+            return true;
+        }
+
+        let diff = self.ack_number.wrapping_sub(sequence);
+        if diff == 0 || diff > ACK_WINDOW {
+            return false;
+        }
+
+        let bit = 1 << (diff - 1);
+        if self.ack_bits & bit != 0 {
+            return false;
+        }
+
+        self.ack_bits |= bit;
+        true
+    }
+
+    pub fn handle_ack(&mut self, ack: u32, ack_bits: u32, now: Instant) {
+        // Only the newest sequence was acked right away; the others could have been acked by
+        // any packet since
+        if let Some(sent) = self.sent_packets.get(&ack) {
+            self.congestion
+                .update_rtt(now.saturating_duration_since(sent.sent_at));
+            trace!(rtt = ?self.congestion.rtt, rtt_var = ?self.congestion.rtt_var, "rtt sample");
+        }
+        self.acknowledge(ack);
+
+        for i in 1..=ACK_WINDOW {
+            if (ack_bits & (1 << (i - 1))) != 0 {
+                self.acknowledge(ack.wrapping_sub(i));
+            }
+        }
+
+        // Anything older than the ack window can never be acked now
+        let oldest = ack.wrapping_sub(ACK_WINDOW);
+        let expired: Vec<u32> = self
+            .sent_packets
+            .keys()
+            .filter(|seq| sequence_greater_than(oldest, **seq))
+            .copied()
+            .collect();
+
+        for seq in expired {
+            self.forget_sent(seq);
+        }
+    }
+
+    fn acknowledge(&mut self, seq: u32) {
+        let Some(sent) = self.sent_packets.remove(&seq) else {
+            return;
+        };
+
+        self.congestion.on_ack();
+        self.stats.packets_acked += 1;
+
+        // A retransmitted message may be acked through more than one sequence
+        if !sent.reliable || self.reliable_packets.remove(&sent.message_id).is_some() {
+            self.events
+                .push_back(DeliveryEvent::Delivered(sent.message_id));
+        }
+    }
+
+    fn forget_sent(&mut self, seq: u32) {
+        if let Some(sent) = self.sent_packets.remove(&seq) {
+            self.stats.packets_lost += 1;
+
+            // Reliable messages are retransmitted instead, and only lost once they give up
+            if !sent.reliable {
+                self.events.push_back(DeliveryEvent::Lost(sent.message_id));
+            }
+        }
+    }
+
+    // The public key, then the compression we offer in a Connect or what was chosen in an
+    // Accept
+    fn send_handshake(&mut self, kind: PacketKind, now: Instant) {
+        let mut data = self.encryption.public_key.clone();
+        match kind {
+            PacketKind::Connect => self.compression.encode_offer(&mut data),
+            _ => self.negotiated.encode(&mut data),
+        }
+
+        let packet = Packet::new(kind, 0, data, now);
+        self.transmit(packet, now);
+    }
+
+    // Where datagrams for the peer go
+    pub fn path(&self) -> SocketAddr {
+        self.relay.unwrap_or(self.peer)
+    }
+
+    fn transmit(&mut self, packet: Packet, now: Instant) {
+        self.transmit_to(packet, self.path(), now);
+    }
+
+    fn transmit_to(&mut self, mut packet: Packet, to: SocketAddr, now: Instant) {
+        if packet.kind == PacketKind::Reliable
+            && !self.reliable_packets.contains_key(&packet.message_id)
+        {
+            // Acked while it was waiting in the queue
+            return;
+        }
+
+        // The sequence is also the nonce, which must never repeat under one key
+        let Some(next_sequence) = self.sequence_number.checked_add(1) else {
+            self.close(DisconnectReason::KeyExhausted);
+            return;
+        };
+
+        packet.connection_id = self.connection_id;
+        packet.sequence = self.sequence_number;
+        packet.ack = self.ack_number;
+        packet.ack_bits = self.ack_bits;
+        self.sequence_number = next_sequence;
+
+        let mut datagram = self.pool.take();
+        datagram.extend_from_slice(&packet.encode_header());
+        datagram.extend_from_slice(&packet.data);
+
+        // Handshake packets carry the public keys the session key is derived from
+        if !matches!(packet.kind, PacketKind::Connect | PacketKind::Accept)
+            && self
+                .encryption
+                .encrypt_in_place(packet.sequence, &mut datagram, HEADER_SIZE)
+                .is_err()
+        {
+            self.pool.put(datagram);
+            return;
+        }
+
+        trace!(kind = ?packet.kind, sequence = packet.sequence, size = datagram.len(), "sent");
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += datagram.len() as u64;
+        self.transmits.push_back((datagram, to));
+
+        // Probes of an unvalidated address don't count as reaching the peer
+        if to == self.path() {
+            self.last_send = now;
+            self.ack_pending = false;
+        }
+
+        let reliable = match packet.kind {
+            PacketKind::Reliable => true,
+            PacketKind::Unreliable => false,
+            _ => return,
+        };
+
+        if let Some(stored) = self.reliable_packets.get_mut(&packet.message_id) {
+            if reliable {
+                stored.sequence = packet.sequence;
+                stored.timestamp = now;
+                stored.attempts += 1;
+            }
+        }
+
+        self.sent_packets.insert(
+            packet.sequence,
+            SentPacket {
+                message_id: packet.message_id,
+                reliable,
+                sent_at: now,
+            },
+        );
+    }
+
+    fn handle_connect(&mut self, packet: Packet, body: &[u8], now: Instant) {
+        if body.len() < PUBLIC_KEY_LEN {
+            return;
+        }
+        let (public_key, offer) = body.split_at(PUBLIC_KEY_LEN);
+
+        match self.state {
+            ProtocolState::Idle => {
+                if self.encryption.establish(public_key, false).is_err() {
+                    debug!("ignored connect with an invalid public key");
+                    return;
+                }
+
+                self.start_compression(self.compression.choose(offer));
+                info!(compression = ?self.negotiated, "accepted connection");
+
+                self.state = ProtocolState::Connected;
+                self.last_activity = now;
+                self.ack_number = packet.sequence;
+                self.ack_bits = 0;
+                self.largest_received = packet.sequence;
+
+                self.send_handshake(PacketKind::Accept, now);
+            }
+            // Our accept was lost, so the peer is still retrying
+            ProtocolState::Connected => {
+                debug!("connect repeated, resending accept");
+                self.send_handshake(PacketKind::Accept, now);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_accept(&mut self, packet: Packet, body: &[u8], now: Instant) {
+        if self.state != ProtocolState::Connecting || body.len() < PUBLIC_KEY_LEN {
+            return;
+        }
+        let (public_key, chosen) = body.split_at(PUBLIC_KEY_LEN);
+        let Some(negotiated) = self.compression.accept(chosen) else {
+            debug!("ignored accept choosing compression we didn't offer");
+            return;
+        };
+
+        if self.encryption.establish(public_key, true).is_err() {
+            debug!("ignored accept with an invalid public key");
+            return;
+        }
+
+        self.start_compression(negotiated);
+        info!(compression = ?self.negotiated, "connected");
+
+        self.state = ProtocolState::Connected;
+        self.last_activity = now;
+        self.ack_number = packet.sequence;
+        self.ack_bits = 0;
+        self.largest_received = packet.sequence;
+    }
+
+    // Challenges an address the peer seems to have moved to. Only one challenge is kept in
+    // flight, and it is re-sent if the address keeps sending without answering.
+    fn challenge_path(&mut self, to: SocketAddr, now: Instant) {
+        if let Some((address, _, sent_at)) = self.path_challenge {
+            if address == to && now.duration_since(sent_at) < self.congestion.retransmit_timeout() {
+                return;
+            }
+        }
+
+        let token = rand::random::<u64>();
+        self.path_challenge = Some((to, token, now));
+        debug!(address = %to, "challenging new address");
+
+        let challenge = Packet::new(
+            PacketKind::PathChallenge,
+            0,
+            token.to_be_bytes().to_vec(),
+            now,
+        );
+        self.transmit_to(challenge, to, now);
+    }
+
+    // Decrypts the datagram in place, so the caller's receive buffer is overwritten
+    pub fn handle_datagram(&mut self, datagram: &mut [u8], from: SocketAddr, now: Instant) {
+        let _span = self.span.clone().entered();
+
+        let Some(mut packet) = Packet::decode(datagram, now) else {
+            return;
+        };
+
+        if packet.connection_id != self.connection_id {
+            return;
+        }
+        trace!(kind = ?packet.kind, sequence = packet.sequence, size = datagram.len(), "received");
+
+        // The relay speaks for the peer
+        let from = match self.relay {
+            Some(relay) if relay == from => self.peer,
+            _ => from,
+        };
+
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += datagram.len() as u64;
+
+        let (header, body) = datagram.split_at_mut(HEADER_SIZE);
+        match packet.kind {
+            PacketKind::Connect => return self.handle_connect(packet, body, now),
+            PacketKind::Accept => return self.handle_accept(packet, body, now),
+            _ => {}
+        }
+
+        if self.state != ProtocolState::Connected {
+            return;
+        }
+
+        let Ok(decrypted) = self
+            .encryption
+            .decrypt_in_place(packet.sequence, header, body)
+        else {
+            self.stats.decrypt_failures += 1;
+            debug!(sequence = packet.sequence, %from, "dropped a datagram that failed to decrypt");
+            return;
+        };
+
+        self.last_activity = now;
+        self.handle_ack(packet.ack, packet.ack_bits, now);
+
+        // Anyone can resend an old packet from another address, so only a new one starts a
+        // migration, and only an answered challenge completes it
+        let fresh = sequence_greater_than(packet.sequence, self.largest_received);
+        if fresh {
+            self.largest_received = packet.sequence;
+        }
+        if fresh && from != self.peer {
+            self.challenge_path(from, now);
+        }
+
+        match packet.kind {
+            PacketKind::Ack => return,
+            PacketKind::PathChallenge => {
+                let response = Packet::new(PacketKind::PathResponse, 0, decrypted.to_vec(), now);
+                return self.transmit_to(response, from, now);
+            }
+            PacketKind::PathResponse => {
+                if let Some((address, token, _)) = self.path_challenge {
+                    // A relayed session upgrades to the direct path this way too
+                    if address == from && *decrypted == token.to_be_bytes() {
+                        info!(from = %self.peer, to = %from, "peer moved");
+                        self.span.record("peer", tracing::field::display(from));
+                        self.peer = from;
+                        self.relay = None;
+                        self.path_challenge = None;
+                    }
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        // Left unacked, so the sender retransmits it once the application has caught up
+        if !self.has_room() {
+            trace!(sequence = packet.sequence, "incoming queue full");
+            return;
+        }
+
+        self.ack_pending = true;
+
+        if !self.record_received(packet.sequence) {
+            trace!(sequence = packet.sequence, "duplicate packet");
+            self.stats.duplicates += 1;
+            return;
+        }
+
+        if packet.kind == PacketKind::Reliable {
+            if !self.received_messages.insert(packet.message_id) {
+                trace!(message_id = packet.message_id, "duplicate message");
+                self.stats.duplicates += 1;
+                return;
+            }
+
+            if self.received_messages.len() > RECEIVED_MESSAGE_HISTORY as usize {
+                let newest = packet.message_id;
+                self.received_messages
+                    .retain(|id| newest.wrapping_sub(*id) < RECEIVED_MESSAGE_HISTORY);
+            }
+        }
+
+        if packet.streamed {
+            return self.receive_streamed(packet, decrypted, now);
+        }
+
+        let codec = match (packet.compressed, self.negotiated.codec(packet.kind)) {
+            (false, _) => Codec::None,
+            // Compressed with a codec that was never agreed on
+            (true, Codec::None) => return,
+            (true, codec) => codec,
+        };
+
+        let mut data = self.pool.get();
+        if let Err(error) = codec.decompress(
+            decrypted,
+            self.negotiated.dictionary_bytes(),
+            &mut data,
+            self.limits.max_message_size,
+        ) {
+            return self.violation(error);
+        }
+
+        packet.data = Payload::Pooled(data);
+        packet.timestamp = now;
+        self.buffer.push_incoming(packet);
+    }
+
+    // Holds streamed messages back until every one before them has been decompressed
+    fn receive_streamed(&mut self, mut packet: Packet, body: &[u8], now: Instant) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        let Some(position) = CompressionStream::position(body) else {
+            return;
+        };
+
+        // Behind the stream, so already delivered
+        if position.wrapping_sub(stream.next_received) >= u32::MAX / 2 {
+            return;
+        }
+
+        if stream.pending_bytes + body.len() > self.limits.max_pending_bytes {
+            return self.close(DisconnectReason::ProtocolViolation(
+                Violation::ReassemblyOverflow,
+            ));
+        }
+
+        let mut data = self.pool.get();
+        data.extend_from_slice(body);
+        packet.data = Payload::Pooled(data);
+        packet.timestamp = now;
+        if let Some(replaced) = stream.pending.insert(position, packet) {
+            stream.pending_bytes -= replaced.data.len();
+        }
+        stream.pending_bytes += body.len();
+
+        while let Some(mut packet) = stream.pending.remove(&stream.next_received) {
+            stream.pending_bytes -= packet.data.len();
+
+            let mut data = self.pool.get();
+            if let Err(error) =
+                stream.decompress(&packet.data, &mut data, self.limits.max_message_size)
+            {
+                // The two sides no longer agree on the stream, and nothing can resync it
+                return self.violation(error);
+            }
+
+            packet.data = Payload::Pooled(data);
+            self.buffer.push_incoming(packet);
+        }
+    }
+
+    // Runs due timers (retransmission, handshake retries, keepalive) and flushes the queue
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let _span = self.span.clone().entered();
+
+        match self.state {
+            ProtocolState::Connected => {
+                let mut retransmit = Vec::new();
+                let mut lost = Vec::new();
+
+                for (message_id, packet) in self.reliable_packets.iter_mut() {
+                    if packet.attempts > 0
+                        && now.duration_since(packet.timestamp)
+                            >= self.congestion.retransmit_timeout()
+                    {
+                        if packet.attempts < MAX_ATTEMPTS {
+                            debug!(message_id, attempts = packet.attempts, "retransmitting");
+                            packet.timestamp = now;
+                            retransmit.push(packet.clone());
+                            self.congestion.on_loss(now);
+                        } else {
+                            lost.push(*message_id);
+                        }
+                    }
+                }
+
+                for message_id in lost {
+                    debug!(message_id, "gave up on message");
+                    self.reliable_packets.remove(&message_id);
+                    self.events.push_back(DeliveryEvent::Lost(message_id));
+                    self.close(DisconnectReason::Unreachable);
+                }
+
+                self.stats.retransmissions += retransmit.len() as u64;
+                for packet in retransmit {
+                    self.buffer.push_outgoing(packet);
+                }
+
+                let timeout = self.timeout;
+                let expired: Vec<u32> = self
+                    .sent_packets
+                    .iter()
+                    .filter(|(_, sent)| now.duration_since(sent.sent_at) >= timeout)
+                    .map(|(seq, _)| *seq)
+                    .collect();
+
+                for seq in expired {
+                    self.forget_sent(seq);
+                }
+            }
+            // Keep retrying the handshake until accepted or timed out
+            ProtocolState::Connecting
+                if now.duration_since(self.last_send) >= self.congestion.retransmit_timeout() =>
+            {
+                debug!("retrying handshake");
+                self.send_handshake(PacketKind::Connect, now);
+            }
+            _ => {}
+        }
+
+        self.flush(now);
+        self.update_state(now);
+    }
+
+    pub fn flush(&mut self, now: Instant) {
+        if self.state != ProtocolState::Connected {
+            return;
+        }
+
+        let mut sent = 0;
+        while sent < self.congestion.window_size {
+            let Some(packet) = self.buffer.outgoing.pop_front() else {
+                break;
+            };
+
+            self.transmit(packet, now);
+            sent += 1;
+        }
+
+        while self.buffer.outgoing.len() < self.buffer.max_size {
+            let Some(message_id) = self.backlog.pop_front() else {
+                break;
+            };
+            if let Some(packet) = self.reliable_packets.get(&message_id) {
+                self.buffer.outgoing.push_back(packet.clone());
+            }
+        }
+
+        // Whatever the window held back waits for the next pass
+        self.next_flush = if self.buffer.outgoing.is_empty() {
+            now
+        } else {
+            now + PACING_INTERVAL
+        };
+
+        // A bare ack doubles as the keepalive when there is nothing else to send
+        if self.ack_pending || now.duration_since(self.last_send) >= self.keepalive {
+            self.transmit(Packet::new(PacketKind::Ack, 0, Vec::new(), now), now);
+        }
+    }
+}
@@ -0,0 +1,854 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    definitions::{
+        Clock, CompressionConfig, ConditionerState, ConnectionStats, Introducer, MessageLimits,
+        NetworkConditions, Packet, ProtocolCore, Relay, SimulatedNat, SimulatedNode, Simulation,
+        VirtualClock,
+    },
+    enums::{ProtocolEvent, ProtocolState},
+};
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            clock: VirtualClock::new(),
+            rng: StdRng::seed_from_u64(seed),
+            nodes: BTreeMap::new(),
+            nats: BTreeMap::new(),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    // Each node's link gets its own seed, drawn in the order nodes are added
+    pub fn add_node(&mut self, addr: SocketAddr, conditions: NetworkConditions) {
+        let link = ConditionerState::new(self.rng.random(), self.now());
+
+        self.nodes.insert(
+            addr,
+            SimulatedNode {
+                core: ProtocolCore::new(),
+                conditions,
+                link,
+                nat: None,
+            },
+        );
+    }
+
+    // A NAT whose public address is `public_ip`; nodes are put behind it with `add_node_behind`
+    pub fn add_nat(&mut self, public_ip: IpAddr) {
+        self.insert_nat(public_ip, false);
+    }
+
+    // A NAT that hole punching can't get through, leaving only a relay
+    pub fn add_symmetric_nat(&mut self, public_ip: IpAddr) {
+        self.insert_nat(public_ip, true);
+    }
+
+    fn insert_nat(&mut self, public_ip: IpAddr, symmetric: bool) {
+        self.nats.insert(
+            public_ip,
+            SimulatedNat {
+                symmetric,
+                next_port: 40000,
+                mappings: BTreeMap::new(),
+                permissions: BTreeSet::new(),
+            },
+        );
+    }
+
+    // `addr` is the node's private address, only reachable from behind the same NAT
+    pub fn add_node_behind(
+        &mut self,
+        addr: SocketAddr,
+        nat: IpAddr,
+        conditions: NetworkConditions,
+    ) -> io::Result<()> {
+        if !self.nats.contains_key(&nat) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no simulated NAT with this address",
+            ));
+        }
+
+        self.add_node(addr, conditions);
+        self.node_mut(addr)?.nat = Some(nat);
+        Ok(())
+    }
+
+    // Takes effect for datagrams sent from now on; those in flight keep their fate
+    pub fn set_conditions(&mut self, addr: SocketAddr, conditions: NetworkConditions) {
+        if let Some(node) = self.nodes.get_mut(&addr) {
+            node.conditions = conditions;
+        }
+    }
+
+    // Moves a node to a new address, as a NAT rebinding or network change would. Datagrams
+    // still in flight to the old address are lost.
+    pub fn rebind(&mut self, from: SocketAddr, to: SocketAddr) -> io::Result<()> {
+        if self.nodes.contains_key(&to) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "a simulated node already has this address",
+            ));
+        }
+
+        let node = self.nodes.remove(&from).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no simulated node at this address")
+        })?;
+        self.nodes.insert(to, node);
+        Ok(())
+    }
+
+    pub fn enable_introducer(
+        &mut self,
+        node: SocketAddr,
+        introducer: Introducer,
+    ) -> io::Result<()> {
+        self.node_mut(node)?.core.enable_introducer(introducer);
+        Ok(())
+    }
+
+    pub fn set_compression(
+        &mut self,
+        node: SocketAddr,
+        compression: CompressionConfig,
+    ) -> io::Result<()> {
+        self.node_mut(node)?.core.compression = compression;
+        Ok(())
+    }
+
+    pub fn set_limits(&mut self, node: SocketAddr, limits: MessageLimits) -> io::Result<()> {
+        self.node_mut(node)?.core.limits = limits;
+        Ok(())
+    }
+
+    pub fn enable_relay(&mut self, node: SocketAddr, relay: Relay) -> io::Result<()> {
+        self.node_mut(node)?.core.enable_relay(relay);
+        Ok(())
+    }
+
+    // Registers `node` with the introducer, offering its own address as the local candidate
+    pub fn register(
+        &mut self,
+        node: SocketAddr,
+        introducer: SocketAddr,
+        peer_id: u64,
+    ) -> io::Result<()> {
+        let now = self.now();
+        self.node_mut(node)?
+            .core
+            .register(introducer, peer_id, vec![node], now);
+        self.collect_transmits(now);
+        Ok(())
+    }
+
+    pub fn punch(&mut self, node: SocketAddr, peer_id: u64) -> io::Result<()> {
+        let now = self.now();
+        self.node_mut(node)?.core.punch(peer_id, now)?;
+        self.collect_transmits(now);
+        Ok(())
+    }
+
+    pub fn connect(&mut self, from: SocketAddr, to: SocketAddr) -> io::Result<()> {
+        let now = self.now();
+        self.node_mut(from)?.core.connect(to, now);
+        self.collect_transmits(now);
+        Ok(())
+    }
+
+    pub fn send_reliable(
+        &mut self,
+        from: SocketAddr,
+        to: SocketAddr,
+        data: Vec<u8>,
+    ) -> io::Result<u32> {
+        let now = self.now();
+        let node = self.node_mut(from)?;
+        let message_id = node.core.send_reliable(to, data, now)?;
+        node.core.flush(now);
+        self.collect_transmits(now);
+        Ok(message_id)
+    }
+
+    pub fn send_unreliable(
+        &mut self,
+        from: SocketAddr,
+        to: SocketAddr,
+        data: Vec<u8>,
+    ) -> io::Result<u32> {
+        let now = self.now();
+        let node = self.node_mut(from)?;
+        let message_id = node.core.send_unreliable(to, data, now)?;
+        node.core.flush(now);
+        self.collect_transmits(now);
+        Ok(message_id)
+    }
+
+    pub fn state(&self, node: SocketAddr, peer: SocketAddr) -> ProtocolState {
+        self.nodes
+            .get(&node)
+            .map_or(ProtocolState::Idle, |node| node.core.state(peer))
+    }
+
+    pub fn stats(&self, node: SocketAddr, peer: SocketAddr) -> Option<ConnectionStats> {
+        self.nodes.get(&node)?.core.stats(peer)
+    }
+
+    pub fn poll_event(&mut self, node: SocketAddr) -> Option<ProtocolEvent> {
+        self.nodes.get_mut(&node)?.core.poll_event()
+    }
+
+    pub fn poll_message(&mut self, node: SocketAddr) -> Option<(SocketAddr, Packet)> {
+        self.nodes.get_mut(&node)?.core.poll_message()
+    }
+
+    // Earliest protocol timer or datagram arrival across every node
+    pub fn next_event(&self) -> Option<Instant> {
+        self.nodes
+            .values()
+            .flat_map(|node| [node.core.next_wakeup(), node.link.next_release()])
+            .flatten()
+            .min()
+    }
+
+    // Jumps the clock to the next event and processes it. Returns false once nothing is scheduled.
+    pub fn step(&mut self) -> bool {
+        let Some(next) = self.next_event() else {
+            return false;
+        };
+
+        self.clock.set(next);
+        let now = self.now();
+
+        let mut arrivals = Vec::new();
+        for (from, node) in self.nodes.iter_mut() {
+            while let Some(delayed) = node.link.pop_due(now) {
+                arrivals.push((*from, delayed));
+            }
+        }
+
+        // Datagrams to addresses without a node vanish, as they would over UDP
+        for (from, mut delayed) in arrivals {
+            let Some((source, destination)) = self.route(from, delayed.to) else {
+                continue;
+            };
+
+            if let Some(node) = self.nodes.get_mut(&destination) {
+                node.core
+                    .handle_datagram(&mut delayed.datagram, source, now);
+            }
+        }
+
+        for node in self.nodes.values_mut() {
+            node.core.handle_timeout(now);
+        }
+
+        self.collect_transmits(now);
+        true
+    }
+
+    // Processes every event up to `duration` from now, then leaves the clock there
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = self.now() + duration;
+
+        while self.next_event().is_some_and(|next| next <= deadline) {
+            self.step();
+        }
+
+        self.clock.set(deadline);
+    }
+
+    // Steps until `done` holds, giving up after `timeout` of simulated time
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut done: impl FnMut(&mut Self) -> bool,
+    ) -> bool {
+        let deadline = self.now() + timeout;
+
+        while !done(self) {
+            if self.next_event().is_none_or(|next| next > deadline) {
+                self.clock.set(deadline);
+                return done(self);
+            }
+
+            self.step();
+        }
+
+        true
+    }
+
+    fn node_mut(&mut self, addr: SocketAddr) -> io::Result<&mut SimulatedNode> {
+        self.nodes.get_mut(&addr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no simulated node at this address")
+        })
+    }
+
+    // Whether `to` is another node behind the same NAT as `from`, so the two talk directly
+    fn same_network(&self, from: SocketAddr, to: SocketAddr) -> bool {
+        let nat = |addr| self.nodes.get(&addr).and_then(|node| node.nat);
+        nat(from).is_some() && nat(from) == nat(to)
+    }
+
+    // The address a datagram from node `from` to `to` arrives from and the node it reaches,
+    // after any NAT on either side. None if a NAT drops it.
+    fn route(&self, from: SocketAddr, to: SocketAddr) -> Option<(SocketAddr, SocketAddr)> {
+        let mut source = from;
+        if !self.same_network(from, to) {
+            if let Some(nat) = self.nodes.get(&from)?.nat {
+                let nat = self.nats.get(&nat)?;
+                source = *nat.mappings.get(&(from, nat.symmetric.then_some(to)))?;
+            }
+
+            // Private addresses aren't reachable from outside
+            if self.nodes.get(&to).is_some_and(|node| node.nat.is_some()) {
+                return None;
+            }
+        }
+
+        let Some(nat) = self.nats.get(&to.ip()) else {
+            return Some((source, to));
+        };
+
+        let ((private, remote), _) = nat.mappings.iter().find(|(_, public)| **public == to)?;
+        (remote.is_none_or(|remote| remote == source)
+            && nat.permissions.contains(&(*private, source)))
+        .then_some((source, *private))
+    }
+
+    // Puts every node's outgoing datagrams onto its link, opening NAT mappings on the way out
+    fn collect_transmits(&mut self, now: Instant) {
+        let addrs: Vec<SocketAddr> = self.nodes.keys().copied().collect();
+
+        for addr in addrs {
+            let mut transmits = Vec::new();
+            if let Some(node) = self.nodes.get_mut(&addr) {
+                while let Some(transmit) = node.core.poll_transmit() {
+                    transmits.push(transmit);
+                }
+            }
+
+            for (datagram, to) in transmits {
+                self.open_mapping(addr, to);
+
+                let node = self.nodes.get_mut(&addr).unwrap();
+                node.link.submit(&node.conditions, &datagram, to, now);
+                node.core.pool.put(datagram);
+            }
+        }
+    }
+
+    fn open_mapping(&mut self, from: SocketAddr, to: SocketAddr) {
+        if self.same_network(from, to) {
+            return;
+        }
+
+        let Some(public_ip) = self.nodes.get(&from).and_then(|node| node.nat) else {
+            return;
+        };
+        let Some(nat) = self.nats.get_mut(&public_ip) else {
+            return;
+        };
+
+        let key = (from, nat.symmetric.then_some(to));
+        if !nat.mappings.contains_key(&key) {
+            let public = SocketAddr::new(public_ip, nat.next_port);
+            nat.next_port = nat.next_port.wrapping_add(1);
+            nat.mappings.insert(key, public);
+        }
+        nat.permissions.insert((from, to));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        definitions::{
+            CompressionConfig, Introducer, MessageLimits, NetworkConditions, Packet, Relay,
+            RelayLimits, Simulation,
+        },
+        enums::{
+            Codec, DeliveryEvent, DisconnectReason, PacketKind, ProtocolEvent, ProtocolState,
+            RendezvousMessage, Violation,
+        },
+        implementations::relay_credential,
+    };
+
+    fn addr(last: u8, port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], port))
+    }
+
+    fn lossy(loss: f64) -> NetworkConditions {
+        NetworkConditions {
+            enabled: true,
+            latency: Duration::from_millis(20),
+            loss,
+            ..NetworkConditions::default()
+        }
+    }
+
+    fn connected(sim: &Simulation, a: SocketAddr, b: SocketAddr) -> bool {
+        sim.state(a, b) == ProtocolState::Connected && sim.state(b, a) == ProtocolState::Connected
+    }
+
+    fn events(sim: &mut Simulation, node: SocketAddr) -> Vec<ProtocolEvent> {
+        std::iter::from_fn(|| sim.poll_event(node)).collect()
+    }
+
+    // Connects `a` to `b` and returns the messages `b` got, after sending `count` from `a`
+    fn exchange(sim: &mut Simulation, a: SocketAddr, b: SocketAddr, count: u8) -> Vec<Vec<u8>> {
+        sim.connect(a, b).unwrap();
+        assert!(sim.run_until(Duration::from_secs(10), |sim| connected(sim, a, b)));
+
+        for i in 0..count {
+            sim.send_reliable(a, b, vec![i; 64]).unwrap();
+        }
+
+        let mut received = Vec::new();
+        sim.run_until(Duration::from_secs(30), |sim| {
+            while let Some((_, packet)) = sim.poll_message(b) {
+                received.push(packet.data.into_vec());
+            }
+            received.len() == count as usize
+        });
+        received
+    }
+
+    #[test]
+    fn handshake_connects_both_sides() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(a, lossy(0.0));
+        sim.add_node(b, lossy(0.0));
+
+        sim.connect(a, b).unwrap();
+        assert!(sim.run_until(Duration::from_secs(1), |sim| connected(sim, a, b)));
+
+        assert!(matches!(events(&mut sim, a)[..], [ProtocolEvent::Connected(peer)] if peer == b));
+        assert!(matches!(events(&mut sim, b)[..], [ProtocolEvent::Connected(peer)] if peer == a));
+    }
+
+    #[test]
+    fn handshake_fails_without_a_peer() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(a, lossy(0.0));
+
+        sim.connect(a, b).unwrap();
+        sim.run_for(Duration::from_secs(10));
+
+        assert_eq!(sim.state(a, b), ProtocolState::Idle);
+        assert!(events(&mut sim, a)
+            .iter()
+            .any(|event| matches!(event, ProtocolEvent::Disconnected(peer, DisconnectReason::TimedOut) if *peer == b)));
+    }
+
+    #[test]
+    fn invalid_handshake_opens_no_session() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(b, lossy(0.0));
+
+        // A Connect without a public key
+        let mut connect = Packet::new(PacketKind::Connect, 0, Vec::new(), sim.now());
+        connect.connection_id = 42;
+        let now = sim.now();
+        let core = &mut sim.nodes.get_mut(&b).unwrap().core;
+        core.handle_datagram(&mut connect.encode_header(), a, now);
+        sim.run_for(Duration::from_secs(10));
+
+        assert_eq!(sim.state(b, a), ProtocolState::Idle);
+        assert!(events(&mut sim, b).is_empty());
+    }
+
+    #[test]
+    fn reliable_messages_survive_loss() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(7);
+        sim.add_node(a, lossy(0.2));
+        sim.add_node(b, lossy(0.2));
+
+        let mut received = exchange(&mut sim, a, b, 30);
+        received.sort();
+
+        let expected: Vec<Vec<u8>> = (0..30).map(|i| vec![i; 64]).collect();
+        assert_eq!(received, expected);
+        assert!(sim.stats(a, b).unwrap().retransmissions > 0);
+    }
+
+    #[test]
+    fn sends_messages_beyond_the_outgoing_queue() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(a, lossy(0.0));
+        sim.add_node(b, lossy(0.0));
+
+        sim.connect(a, b).unwrap();
+        assert!(sim.run_until(Duration::from_secs(1), |sim| connected(sim, a, b)));
+
+        // Queued without flushing in between, as an endpoint does until its next update
+        let now = sim.now();
+        let core = &mut sim.nodes.get_mut(&a).unwrap().core;
+        core.sessions.get_mut(&b).unwrap().buffer.max_size = 8;
+        for i in 0..100 {
+            core.send_reliable(b, vec![i; 64], now).unwrap();
+        }
+
+        let mut received = Vec::new();
+        sim.run_until(Duration::from_secs(10), |sim| {
+            while let Some((_, packet)) = sim.poll_message(b) {
+                received.push(packet.data.into_vec());
+            }
+            received.len() == 100
+        });
+        received.sort();
+
+        let expected: Vec<Vec<u8>> = (0..100).map(|i| vec![i; 64]).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn drops_a_peer_whose_message_decompresses_past_the_limit() {
+        for codec in [Codec::Zlib(6), Codec::Lz4, Codec::Zstd(3)] {
+            let (a, b) = (addr(1, 1000), addr(2, 2000));
+            let mut sim = Simulation::new(1);
+            for node in [a, b] {
+                sim.add_node(node, lossy(0.0));
+                let compression = CompressionConfig {
+                    reliable: vec![codec],
+                    ..CompressionConfig::default()
+                };
+                sim.set_compression(node, compression).unwrap();
+            }
+            let limits = MessageLimits {
+                max_message_size: 1024,
+                ..MessageLimits::default()
+            };
+            sim.set_limits(b, limits).unwrap();
+
+            sim.connect(a, b).unwrap();
+            assert!(sim.run_until(Duration::from_secs(10), |sim| connected(sim, a, b)));
+            events(&mut sim, b);
+
+            // Compresses to a few bytes, so it fits in one datagram
+            sim.send_reliable(a, b, vec![0; 64 * 1024]).unwrap();
+            sim.run_for(Duration::from_millis(500));
+
+            assert_eq!(sim.state(b, a), ProtocolState::Idle);
+            assert!(sim.poll_message(b).is_none());
+            let b_events = events(&mut sim, b);
+            assert!(
+                matches!(
+                    b_events[..],
+                    [ProtocolEvent::Disconnected(
+                        peer,
+                        DisconnectReason::ProtocolViolation(Violation::MessageTooLarge)
+                    )] if peer == a
+                ),
+                "{:?}: {:?}",
+                codec,
+                b_events
+            );
+        }
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(a, lossy(0.0));
+        sim.add_node(b, lossy(0.0));
+
+        sim.connect(a, b).unwrap();
+        assert!(sim.run_until(Duration::from_secs(1), |sim| connected(sim, a, b)));
+        events(&mut sim, a);
+
+        // Cut the link in both directions
+        sim.set_conditions(a, lossy(1.0));
+        sim.set_conditions(b, lossy(1.0));
+        sim.run_for(Duration::from_secs(10));
+
+        assert_eq!(sim.state(a, b), ProtocolState::Idle);
+        assert!(matches!(
+            events(&mut sim, a)[..],
+            [ProtocolEvent::Disconnected(peer, DisconnectReason::TimedOut)] if peer == b
+        ));
+    }
+
+    #[test]
+    fn disconnects_before_reusing_a_nonce() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(a, lossy(0.0));
+        sim.add_node(b, lossy(0.0));
+
+        sim.connect(a, b).unwrap();
+        assert!(sim.run_until(Duration::from_secs(1), |sim| connected(sim, a, b)));
+        events(&mut sim, a);
+
+        let session = sim
+            .nodes
+            .get_mut(&a)
+            .unwrap()
+            .core
+            .sessions
+            .get_mut(&b)
+            .unwrap();
+        session.sequence_number = u32::MAX - 1;
+        sim.send_reliable(a, b, vec![1; 64]).unwrap();
+        sim.send_reliable(a, b, vec![2; 64]).unwrap();
+        sim.run_for(Duration::from_millis(100));
+
+        assert_eq!(sim.poll_message(b).unwrap().1.data.into_vec(), vec![1; 64]);
+        assert!(sim.poll_message(b).is_none());
+        assert!(matches!(
+            events(&mut sim, a)[..],
+            [.., ProtocolEvent::Disconnected(peer, DisconnectReason::KeyExhausted)] if peer == b
+        ));
+    }
+
+    #[test]
+    fn counts_receipts_that_overflow_the_event_queue() {
+        let (a, b) = (addr(1, 1000), addr(2, 2000));
+        let mut sim = Simulation::new(1);
+        sim.add_node(a, lossy(0.0));
+        sim.add_node(b, lossy(0.0));
+
+        // An endpoint that has left thousands of events undrained
+        let filler = ProtocolEvent::Registered(a);
+        let core = &mut sim.nodes.get_mut(&a).unwrap().core;
+        core.events.extend(std::iter::repeat_n(filler, 4096));
+
+        exchange(&mut sim, a, b, 10);
+        sim.run_for(Duration::from_secs(1));
+
+        let events = events(&mut sim, a);
+        assert!(events[..4096].iter().all(|event| *event == filler));
+        assert_eq!(
+            events[4096..],
+            [
+                ProtocolEvent::Connected(b),
+                ProtocolEvent::Delivery(b, DeliveryEvent::Unreported(10))
+            ]
+        );
+    }
+
+    #[test]
+    fn same_seed_repeats_a_run() {
+        let run = |seed| {
+            let (a, b) = (addr(1, 1000), addr(2, 2000));
+            let mut sim = Simulation::new(seed);
+            let started = sim.now();
+            sim.add_node(a, lossy(0.2));
+            sim.add_node(b, lossy(0.2));
+
+            exchange(&mut sim, a, b, 30);
+            let stats = sim.stats(a, b).unwrap();
+            (
+                sim.now() - started,
+                stats.packets_sent,
+                stats.retransmissions,
+            )
+        };
+
+        assert_eq!(run(3), run(3));
+    }
+
+    const INTRODUCER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 3478);
+    const RELAY: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(4, 4, 4, 4)), 3479);
+    const NAT_A: IpAddr = IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2));
+    const NAT_B: IpAddr = IpAddr::V4(Ipv4Addr::new(3, 3, 3, 3));
+
+    // Two peers behind their own NATs, both registered with an introducer on the open internet.
+    // Peer 1 is at `a`, peer 2 at `b`.
+    fn behind_nats(
+        symmetric_b: bool,
+        introducer: Introducer,
+    ) -> (Simulation, SocketAddr, SocketAddr) {
+        let a = SocketAddr::from(([192, 168, 1, 2], 5000));
+        let b = SocketAddr::from(([192, 168, 2, 2], 5000));
+        let mut sim = Simulation::new(1);
+
+        sim.add_node(INTRODUCER, lossy(0.0));
+        sim.enable_introducer(INTRODUCER, introducer).unwrap();
+        sim.add_nat(NAT_A);
+        if symmetric_b {
+            sim.add_symmetric_nat(NAT_B);
+        } else {
+            sim.add_nat(NAT_B);
+        }
+        sim.add_node_behind(a, NAT_A, lossy(0.0)).unwrap();
+        sim.add_node_behind(b, NAT_B, lossy(0.0)).unwrap();
+
+        sim.register(a, INTRODUCER, 1).unwrap();
+        sim.register(b, INTRODUCER, 2).unwrap();
+        sim.run_for(Duration::from_millis(500));
+
+        for node in [a, b] {
+            assert!(matches!(
+                events(&mut sim, node)[..],
+                [ProtocolEvent::Registered(public)] if public.ip() != node.ip()
+            ));
+        }
+        (sim, a, b)
+    }
+
+    #[test]
+    fn punches_through_cone_nats() {
+        let (mut sim, a, b) = behind_nats(false, Introducer::new(Duration::from_secs(60)));
+
+        sim.punch(a, 2).unwrap();
+        sim.run_for(Duration::from_secs(2));
+
+        let a_events = events(&mut sim, a);
+        let Some(b_public) = a_events.iter().find_map(|event| match event {
+            ProtocolEvent::PunchSucceeded(2, addr) => Some(*addr),
+            _ => None,
+        }) else {
+            panic!("punch didn't succeed: {:?}", a_events);
+        };
+
+        assert_eq!(b_public.ip(), NAT_B);
+        assert_eq!(sim.state(a, b_public), ProtocolState::Connected);
+        assert!(events(&mut sim, b)
+            .iter()
+            .any(|event| matches!(event, ProtocolEvent::Connected(peer) if peer.ip() == NAT_A)));
+
+        sim.send_reliable(a, b_public, b"through the NAT".to_vec())
+            .unwrap();
+        sim.run_for(Duration::from_millis(500));
+        let (_, packet) = sim.poll_message(b).unwrap();
+        assert_eq!(&*packet.data, b"through the NAT");
+    }
+
+    #[test]
+    fn punch_to_an_unregistered_peer_fails() {
+        let (mut sim, a, _) = behind_nats(false, Introducer::new(Duration::from_secs(60)));
+
+        sim.punch(a, 3).unwrap();
+        sim.run_for(Duration::from_secs(1));
+
+        assert!(matches!(
+            events(&mut sim, a)[..],
+            [ProtocolEvent::PunchFailed(3)]
+        ));
+    }
+
+    #[test]
+    fn punch_fails_behind_a_symmetric_nat() {
+        let (mut sim, a, b) = behind_nats(true, Introducer::new(Duration::from_secs(60)));
+
+        sim.punch(a, 2).unwrap();
+        sim.run_for(Duration::from_secs(15));
+
+        assert!(matches!(
+            events(&mut sim, a)[..],
+            [ProtocolEvent::PunchFailed(2)]
+        ));
+        assert!(events(&mut sim, b)
+            .iter()
+            .all(|event| !matches!(event, ProtocolEvent::Connected(_))));
+    }
+
+    // The introducer signs relay credentials with `offered`, and the relay checks them
+    // against `secret`
+    fn relayed(offered: &[u8], secret: &[u8]) -> (Simulation, SocketAddr, SocketAddr) {
+        let introducer = Introducer::new(Duration::from_secs(60)).with_relay(RELAY, offered);
+        let (mut sim, a, b) = behind_nats(true, introducer);
+
+        sim.add_node(RELAY, lossy(0.0));
+        let relay = Relay::new(secret, RelayLimits::default(), sim.now());
+        sim.enable_relay(RELAY, relay).unwrap();
+
+        sim.punch(a, 2).unwrap();
+        sim.run_for(Duration::from_secs(15));
+        (sim, a, b)
+    }
+
+    #[test]
+    fn falls_back_to_the_relay() {
+        let (mut sim, a, b) = relayed(b"secret", b"secret");
+
+        let a_events = events(&mut sim, a);
+        let Some(b_public) = a_events.iter().find_map(|event| match event {
+            ProtocolEvent::Relayed(2, addr) => Some(*addr),
+            _ => None,
+        }) else {
+            panic!("didn't fall back to the relay: {:?}", a_events);
+        };
+        assert!(a_events
+            .iter()
+            .any(|event| matches!(event, ProtocolEvent::Connected(peer) if *peer == b_public)));
+        assert_eq!(sim.state(a, b_public), ProtocolState::Connected);
+
+        sim.send_reliable(a, b_public, b"through the relay".to_vec())
+            .unwrap();
+        sim.run_for(Duration::from_millis(500));
+        let (_, packet) = sim.poll_message(b).unwrap();
+        assert_eq!(&*packet.data, b"through the relay");
+    }
+
+    #[test]
+    fn relay_refuses_a_bad_credential() {
+        let (mut sim, a, b) = relayed(b"forged", b"secret");
+
+        assert!(matches!(
+            events(&mut sim, a)[..],
+            [ProtocolEvent::PunchFailed(2)]
+        ));
+        assert!(events(&mut sim, b)
+            .iter()
+            .all(|event| !matches!(event, ProtocolEvent::Connected(_))));
+        assert!(sim.nodes[&RELAY]
+            .core
+            .relay
+            .as_ref()
+            .is_some_and(|relay| relay.allocations.is_empty()));
+    }
+
+    #[test]
+    fn relay_credentials_only_claim_their_own_side() {
+        let now = Instant::now();
+        let mut relay = Relay::new(b"secret", RelayLimits::default(), now);
+        let secret = relay.secret.clone();
+        let allocate = |relay: &mut Relay, initiator, credential_side, from| {
+            let mut replies = Vec::new();
+            let message = RendezvousMessage::Allocate {
+                connection_id: 7,
+                initiator,
+                credential: relay_credential(&secret, 7, credential_side),
+            };
+            relay.handle_message(message, from, now, &mut replies);
+            matches!(replies[..], [(RendezvousMessage::Allocated { .. }, _)])
+        };
+        let (a, b, thief) = (addr(1, 1000), addr(2, 2000), addr(9, 9000));
+
+        assert!(allocate(&mut relay, true, true, a));
+        // The initiator's credential, replayed for the other side, is refused
+        assert!(!allocate(&mut relay, false, true, thief));
+        assert!(allocate(&mut relay, false, false, b));
+        assert_eq!(relay.forward(7, 10, a, now), Some(b));
+
+        // The same side from a new address takes over its own slot
+        let rebound = addr(1, 1001);
+        assert!(allocate(&mut relay, true, true, rebound));
+        assert_eq!(relay.forward(7, 10, b, now), Some(rebound));
+        assert_eq!(relay.forward(7, 10, a, now), None);
+    }
+}
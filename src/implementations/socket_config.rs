@@ -0,0 +1,222 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::definitions::SocketConfig;
+
+// Binds with the default configuration: OS buffer sizes and a dual-stack socket for IPv6
+pub fn bind_socket<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+    SocketConfig::default().bind(addr)
+}
+
+impl SocketConfig {
+    // Binds a non-blocking socket on the first address that works, like `UdpSocket::bind`.
+    // IPv6 sockets are dual-stack unless `only_v6` is set, so `[::]:port` also serves IPv4 peers.
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<UdpSocket> {
+        let mut last_error = None;
+
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_one(addr) {
+                Ok(socket) => return Ok(socket),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    fn bind_one(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+        if addr.is_ipv6() {
+            if self.only_v6 {
+                socket.set_only_v6(true)?;
+            } else {
+                // Not every platform allows dual-stack sockets, which then stay IPv6-only
+                let _ = socket.set_only_v6(false);
+            }
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if self.reuse_address {
+            socket.set_reuse_address(true)?;
+        }
+        if self.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        if let Some(dscp) = self.dscp {
+            set_dscp(&socket, addr, dscp)?;
+        }
+        if self.dont_fragment {
+            set_dont_fragment(&socket, addr)?;
+        }
+
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+
+        Ok(socket.into())
+    }
+}
+
+fn set_dscp(socket: &Socket, addr: SocketAddr, dscp: u8) -> io::Result<()> {
+    if dscp > 63 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "DSCP is a 6-bit value",
+        ));
+    }
+
+    // DSCP is the top six bits of the IPv4 TOS / IPv6 traffic class byte
+    let tos = u32::from(dscp) << 2;
+    if addr.is_ipv4() {
+        return set_tos(socket, tos);
+    }
+
+    set_traffic_class(socket, tos)?;
+
+    // IPv4 peers of a dual-stack socket take their marking from IP_TOS
+    let _ = set_tos(socket, tos);
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+)))]
+fn set_tos(socket: &Socket, tos: u32) -> io::Result<()> {
+    socket.set_tos(tos)
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+))]
+fn set_tos(_socket: &Socket, _tos: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking is not supported on this platform",
+    ))
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_traffic_class(socket: &Socket, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_traffic_class(_socket: &Socket, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking of IPv6 traffic is not supported on this platform",
+    ))
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_dont_fragment(socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    fn set_option(
+        socket: &Socket,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    if addr.is_ipv4() {
+        return set_option(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        );
+    }
+
+    set_option(
+        socket,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_MTU_DISCOVER,
+        libc::IPV6_PMTUDISC_DO,
+    )?;
+
+    // Covers IPv4 peers of a dual-stack socket
+    let _ = set_option(
+        socket,
+        libc::IPPROTO_IP,
+        libc::IP_MTU_DISCOVER,
+        libc::IP_PMTUDISC_DO,
+    );
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_dont_fragment(_socket: &Socket, _addr: SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "don't-fragment is not supported on this platform",
+    ))
+}
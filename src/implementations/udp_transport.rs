@@ -0,0 +1,151 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use std::{mem, os::fd::AsRawFd, ptr};
+
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
+
+use crate::definitions::Transport;
+
+// Most datagrams moved by one recvmmsg/sendmmsg call
+#[cfg(target_os = "linux")]
+const MMSG_BATCH: usize = 32;
+
+// Sessions are keyed by plain IPv4 addresses, which an IPv6 socket can only reach mapped
+pub(crate) fn destination(to: SocketAddr, socket_is_ipv6: bool) -> SocketAddr {
+    match to {
+        SocketAddr::V4(v4) if socket_is_ipv6 => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        to => to,
+    }
+}
+
+// Expects the socket to be in non-blocking mode, as `bind_socket` leaves it
+impl Transport for UdpSocket {
+    fn send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let to = destination(to, self.local_addr()?.is_ipv6());
+        UdpSocket::send_to(self, datagram, to)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_nonblocking(false)?;
+        self.set_read_timeout(timeout)?;
+
+        // Errors here (timeouts included) surface from the next `recv_from` instead
+        let _ = self.peek_from(&mut [0u8; 1]);
+
+        self.set_nonblocking(true)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(
+        &self,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        received.clear();
+
+        // Safety: all-zero is a valid value for these plain C structs
+        let mut iovecs: [libc::iovec; MMSG_BATCH] = unsafe { mem::zeroed() };
+        let mut addrs: [libc::sockaddr_storage; MMSG_BATCH] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; MMSG_BATCH] = unsafe { mem::zeroed() };
+        let count = bufs.len().min(MMSG_BATCH);
+
+        for (((buf, iovec), addr), header) in bufs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(addrs.iter_mut())
+            .zip(headers.iter_mut())
+        {
+            iovec.iov_base = buf.as_mut_ptr().cast();
+            iovec.iov_len = buf.len();
+            header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                self.as_raw_fd(),
+                headers.as_mut_ptr(),
+                count as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (addr, header) in addrs.iter().zip(&headers).take(result as usize) {
+            // Safety: the kernel wrote a socket address of `msg_namelen` bytes
+            let from = unsafe { SockAddr::new(*addr, header.msg_hdr.msg_namelen) };
+            if let Some(from) = from.as_socket() {
+                received.push((header.msg_len as usize, from));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_batch(&self, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+        let socket_is_ipv6 = self.local_addr()?.is_ipv6();
+
+        // Safety: all-zero is a valid value for these plain C structs
+        let mut iovecs: [libc::iovec; MMSG_BATCH] = unsafe { mem::zeroed() };
+        let mut addrs: [libc::sockaddr_storage; MMSG_BATCH] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; MMSG_BATCH] = unsafe { mem::zeroed() };
+        let count = datagrams.len().min(MMSG_BATCH);
+
+        for ((((datagram, to), iovec), addr), header) in datagrams
+            .iter()
+            .map(|(datagram, to)| (datagram, destination(*to, socket_is_ipv6)))
+            .zip(iovecs.iter_mut())
+            .zip(addrs.iter_mut())
+            .zip(headers.iter_mut())
+        {
+            let to = SockAddr::from(to);
+
+            // Safety: `to` is at most a sockaddr_storage long
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    to.as_ptr().cast::<u8>(),
+                    (addr as *mut libc::sockaddr_storage).cast::<u8>(),
+                    to.len() as usize,
+                );
+            }
+
+            // The kernel only reads from the buffer
+            iovec.iov_base = datagram.as_ptr() as *mut libc::c_void;
+            iovec.iov_len = datagram.len();
+            header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            header.msg_hdr.msg_namelen = to.len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let result =
+            unsafe { libc::sendmmsg(self.as_raw_fd(), headers.as_mut_ptr(), count as _, 0) };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(result as usize)
+    }
+}
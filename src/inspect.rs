@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, BufWriter, ErrorKind, Write},
+    net::{IpAddr, SocketAddr},
+    process,
+    sync::Arc,
+    time::Duration,
+};
+
+use dserve::{
+    definitions::{
+        CapturedDatagram, CompressionDictionary, InspectedPacket, Inspector, PacketCapture,
+    },
+    enums::{PacketKind, ProtocolState},
+    game_server::types::GameMessage,
+    implementations::read_keylog,
+};
+
+// Sessions give up on a peer they haven't heard from in this long
+const SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "usage: dserve-inspect <capture> [--keylog <file>] [--dictionary <file>] \
+                     [--peer <address>] [--type <type>[,<type>...]]";
+
+// Which packets to print
+struct Filter {
+    // An address, or just an IP, at either end
+    peer: Option<String>,
+    // Packet kinds, game message names, "rendezvous" or "retransmission", in lowercase
+    types: Vec<String>,
+}
+
+// Prints a timeline of the traffic in a capture, such as one written with DSERVE_PCAP set.
+// With the keylog written alongside it (DSERVE_KEYLOG, in builds with the keylog feature)
+// packets are decrypted and the game messages in them decoded.
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let mut capture = None;
+    let mut keys = HashMap::new();
+    let mut dictionary = None;
+    let mut filter = Filter {
+        peer: None,
+        types: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--keylog" => keys = read_keylog(value())?,
+            "--dictionary" => {
+                dictionary = Some(Arc::new(CompressionDictionary::load(value())?));
+            }
+            "--peer" => filter.peer = Some(value()),
+            "--type" => filter
+                .types
+                .extend(value().split(',').map(str::to_lowercase)),
+            _ if capture.is_none() && !arg.starts_with("--") => capture = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(capture) = capture else { usage() };
+
+    let datagrams = PacketCapture::read(&capture)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", capture, e)))?;
+    if datagrams.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "capture is empty"));
+    }
+
+    // Stopping early, as when piped into `head`, isn't an error
+    match timeline(datagrams, Inspector::new(keys, dictionary), &filter) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+fn timeline(
+    datagrams: Vec<CapturedDatagram>,
+    mut inspector: Inspector,
+    filter: &Filter,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    // Clocks can step back mid-capture, and tcpdump on several interfaces interleaves them, but
+    // the inspector still needs the datagrams in capture order
+    let timestamps = datagrams.iter().map(|datagram| datagram.timestamp);
+    let start = timestamps.clone().min().unwrap_or_default();
+    let end = timestamps.max().unwrap_or_default();
+
+    for datagram in datagrams {
+        let inspected = inspector.inspect(datagram);
+        let messages: Vec<_> = inspected
+            .messages
+            .iter()
+            .map(|message| GameMessage::decode(message))
+            .collect();
+
+        if filter.matches(&inspected, &messages) {
+            print(&mut out, &inspector, &inspected, &messages, start)?;
+        }
+    }
+
+    writeln!(out)?;
+    for (id, connection) in &inspector.connections {
+        writeln!(
+            out,
+            "{:016x}: {} packets, {} bytes, {} retransmitted, {} undecryptable, {:.3}s to {:.3}s",
+            id,
+            connection.packets,
+            connection.bytes,
+            connection.retransmissions,
+            connection.decrypt_failures,
+            connection.first_seen.saturating_sub(start).as_secs_f64(),
+            connection.last_seen.saturating_sub(start).as_secs_f64(),
+        )?;
+
+        // Nothing on the wire says a session ended, but a silent one times out
+        if connection.state == ProtocolState::Connected
+            && end.saturating_sub(connection.last_seen) >= SESSION_TIMEOUT
+        {
+            writeln!(
+                out,
+                "{:>10.6} {:016x} state -> timed out",
+                (connection.last_seen + SESSION_TIMEOUT - start).as_secs_f64(),
+                id
+            )?;
+        }
+    }
+
+    out.flush()
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+impl Filter {
+    fn matches(
+        &self,
+        inspected: &InspectedPacket,
+        messages: &[bincode::Result<GameMessage>],
+    ) -> bool {
+        let datagram = &inspected.datagram;
+        let peer_matches = self.peer.as_ref().is_none_or(|peer| {
+            [datagram.from, datagram.to]
+                .iter()
+                .any(|address| match peer.parse::<IpAddr>() {
+                    Ok(ip) => address.ip() == ip,
+                    Err(_) => peer.parse::<SocketAddr>() == Ok(*address),
+                })
+        });
+        if !peer_matches {
+            return false;
+        }
+
+        if self.types.is_empty() {
+            return true;
+        }
+
+        let mut types = Vec::new();
+        if let Some(packet) = &inspected.packet {
+            types.push(format!("{:?}", packet.kind).to_lowercase());
+        }
+        if inspected.rendezvous.is_some() {
+            types.push("rendezvous".to_string());
+        }
+        if inspected.retransmission {
+            types.push("retransmission".to_string());
+        }
+        types.extend(
+            messages
+                .iter()
+                .flatten()
+                .map(|message| variant(message).to_lowercase()),
+        );
+
+        types.iter().any(|name| self.types.contains(name))
+    }
+}
+
+// The name of a game message's variant, without its fields
+fn variant(message: &GameMessage) -> String {
+    format!("{:?}", message)
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+fn print(
+    out: &mut impl Write,
+    inspector: &Inspector,
+    inspected: &InspectedPacket,
+    messages: &[bincode::Result<GameMessage>],
+    start: Duration,
+) -> io::Result<()> {
+    let datagram = &inspected.datagram;
+    let elapsed = datagram.timestamp.saturating_sub(start).as_secs_f64();
+    let id = inspected
+        .packet
+        .as_ref()
+        .map_or(String::from("-"), |packet| {
+            format!("{:016x}", packet.connection_id)
+        });
+
+    let mut line = format!(
+        "{:>10.6} {:>16} {} -> {} {}B ",
+        elapsed,
+        id,
+        datagram.from,
+        datagram.to,
+        datagram.data.len()
+    );
+    match (&inspected.packet, &inspected.rendezvous) {
+        (Some(packet), _) => {
+            line += &format!(
+                "{:?} seq {} ack {} bits {:08x}",
+                packet.kind, packet.sequence, packet.ack, packet.ack_bits
+            );
+            if packet.kind == PacketKind::Reliable {
+                line += &format!(" message {}", packet.message_id);
+            }
+            if packet.compressed {
+                line += " compressed";
+            }
+            if packet.streamed {
+                line += " streamed";
+            }
+        }
+        (None, Some(rendezvous)) => line += &format!("{:?}", rendezvous),
+        (None, None) => line += "?",
+    }
+    if inspected.retransmission {
+        line += " (retransmission)";
+    }
+    writeln!(out, "{}", line)?;
+
+    if let Some(error) = &inspected.error {
+        writeln!(out, "{:>10} ! {}", "", error)?;
+    }
+    for message in messages {
+        match message {
+            Ok(message) => writeln!(out, "{:>10}   {:?}", "", message)?,
+            Err(e) => writeln!(out, "{:>10} ! undecodable message: {}", "", e)?,
+        }
+    }
+
+    if let (Some(state), Some(packet)) = (inspected.transition, &inspected.packet) {
+        let connection = &inspector.connections[&packet.connection_id];
+        let mut line = format!("{:>10.6} {} state -> {:?}", elapsed, id, state);
+        if state == ProtocolState::Connected {
+            let negotiated = &connection.negotiated;
+            line += &format!(
+                ", reliable {:?}, unreliable {:?}",
+                negotiated.reliable, negotiated.unreliable
+            );
+            if let Some(dictionary) = &negotiated.dictionary {
+                line += &format!(", dictionary {}", dictionary.id);
+            }
+            if negotiated.streaming {
+                line += ", streaming";
+            }
+        }
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::env;
+
+use dserve::{
+    definitions::{def, Introducer},
+    implementations::init_logging,
+};
+use tracing::info;
+
+// Rendezvous point for peers behind NATs: they register here, and it tells each side of a
+// pair where to punch when one asks for the other. Given a relay's address and secret, it
+// also offers that relay in case punching fails.
+fn main() -> std::io::Result<()> {
+    init_logging();
+
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "[::]:3478".to_string());
+
+    let mut introducer = Introducer::default();
+    if let Some(relay) = env::args().nth(2) {
+        let relay = relay
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let secret = env::args()
+            .nth(3)
+            .or_else(|| env::var("DSERVE_RELAY_SECRET").ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "a relay needs its secret as well, or DSERVE_RELAY_SECRET",
+                )
+            })?;
+        introducer = introducer.with_relay(relay, secret.as_bytes());
+    }
+
+    let mut protocol = def::NetworkProtocol::new(addr)?;
+    protocol.enable_introducer(introducer);
+
+    info!("Introducer started on {}", protocol.local_addr()?);
+
+    loop {
+        protocol.wait(protocol.next_wakeup())?;
+        protocol.update()?;
+
+        // Sessions log their own end, so the events are only drained
+        while protocol.poll_event().is_some() {}
+    }
+}
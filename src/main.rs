@@ -3,18 +3,76 @@
 // mod enums;
 // mod implementations;
 
-use std::time::Duration;
+use std::{
+    env,
+    sync::{Arc, Mutex},
+};
 
-use dserve::definitions::def;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+use dserve::{definitions::def, implementations::init_logging};
+use tracing::info;
 
 fn main() -> std::io::Result<()> {
-    let mut protocol = def::NetworkProtocol::new("127.0.0.1:3800")?;
+    init_logging();
+
+    // Loopback by default; pass [::]:3800 to take IPv4 and IPv6 clients on every interface
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:3800".to_string());
+    // Roomy kernel buffers so bursts from many players aren't dropped, marked as real-time traffic
+    let config = def::SocketConfig {
+        recv_buffer_size: Some(4 * 1024 * 1024),
+        send_buffer_size: Some(4 * 1024 * 1024),
+        dscp: Some(46),
+        ..Default::default()
+    };
+    let socket = config.bind(addr)?;
+    let local_addr = socket.local_addr()?;
+
+    // Starts as a perfect link; type `on` and other commands into stdin to degrade it
+    let conditions = Arc::new(Mutex::new(def::NetworkConditions::default()));
+    def::NetworkConditions::spawn_console(conditions.clone());
+
+    let mut protocol =
+        def::NetworkProtocol::with_transport(def::ConditionedTransport::new(socket, conditions, 0));
+
+    info!("Server started on {}", local_addr);
 
-    println!("Server started on 127.0.0.1:3800");
+    // Packets for offline inspection, and in development builds the keys to decrypt them
+    if let Ok(path) = env::var("DSERVE_PCAP") {
+        protocol.capture_to(&path)?;
+        info!("Capturing packets to {}", path);
+    }
+    #[cfg(feature = "keylog")]
+    if let Ok(path) = env::var("DSERVE_KEYLOG") {
+        protocol.keylog_to(&path)?;
+        info!("Writing session keys to {}", path);
+    }
+
+    #[cfg(feature = "metrics")]
+    let metrics = {
+        let metrics = Arc::new(Mutex::new(def::Metrics::new(Instant::now())));
+        let addr = env::var("DSERVE_METRICS").unwrap_or_else(|_| "127.0.0.1:9100".to_string());
+        def::Metrics::serve(metrics.clone(), &addr)?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        metrics
+    };
 
     loop {
+        // Sleep until there is traffic or a timer is due
+        protocol.wait(protocol.next_wakeup())?;
         protocol.update()?;
-        // Using 60fps update rate
-        std::thread::sleep(Duration::from_millis(16));
+
+        // Nothing here reads them, so they're drained rather than left to pile up
+        while protocol.poll_event().is_some() {}
+        while protocol.poll_message().is_some() {}
+
+        #[cfg(feature = "metrics")]
+        metrics
+            .lock()
+            .unwrap()
+            .record(&protocol.core, Instant::now());
     }
 }
@@ -0,0 +1,47 @@
+use std::{env, time::Instant};
+
+use dserve::{
+    definitions::{def, Relay, RelayLimits, SocketConfig},
+    implementations::init_logging,
+};
+use tracing::info;
+
+// Forwards datagrams between peers that couldn't punch through to each other. Its secret
+// must match the introducer's, which hands out the credentials for each allocation.
+fn main() -> std::io::Result<()> {
+    init_logging();
+
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "[::]:3479".to_string());
+    let secret = env::args()
+        .nth(2)
+        .or_else(|| env::var("DSERVE_RELAY_SECRET").ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "usage: relay [address] <secret>, or set DSERVE_RELAY_SECRET",
+            )
+        })?;
+
+    // Relaying for many peers needs more socket buffer than the defaults
+    let config = SocketConfig {
+        recv_buffer_size: Some(4 * 1024 * 1024),
+        send_buffer_size: Some(4 * 1024 * 1024),
+        ..SocketConfig::default()
+    };
+
+    let mut protocol = def::NetworkProtocol::with_config(addr, &config)?;
+    let relay = Relay::new(secret.as_bytes(), RelayLimits::default(), Instant::now());
+    protocol.enable_relay(relay);
+
+    info!("Relay started on {}", protocol.local_addr()?);
+
+    loop {
+        protocol.wait(protocol.next_wakeup())?;
+        protocol.update()?;
+
+        // Sessions log their own end, so the events are only drained
+        while protocol.poll_event().is_some() {}
+    }
+}
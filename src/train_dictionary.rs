@@ -0,0 +1,42 @@
+use std::{env, fs::File, io::BufReader};
+
+use dserve::{definitions::CompressionDictionary, implementations::read_samples};
+
+// Dictionaries this size suit messages of up to a few kilobytes
+const DEFAULT_MAX_SIZE: usize = 16 * 1024;
+
+// Trains a compression dictionary from a capture of typical messages, such as the one the
+// game server writes with DSERVE_CAPTURE set. Peers only use it with others that load a
+// dictionary with the same id, so give each retrained dictionary a new one.
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: train_dictionary <capture> <output> [id] [max size]");
+        std::process::exit(2);
+    }
+
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let id = match args.get(3) {
+        Some(id) => id.parse().map_err(invalid)?,
+        None => 1,
+    };
+    let max_size = match args.get(4) {
+        Some(size) => size.parse().map_err(invalid)?,
+        None => DEFAULT_MAX_SIZE,
+    };
+
+    let samples = read_samples(&mut BufReader::new(File::open(&args[1])?))?;
+    let total: usize = samples.iter().map(Vec::len).sum();
+    println!("Training on {} samples, {} bytes", samples.len(), total);
+
+    let dictionary = CompressionDictionary::train(id, &samples, max_size)?;
+    dictionary.save(&args[2])?;
+
+    println!(
+        "Wrote dictionary {} ({} bytes) to {}",
+        dictionary.id,
+        dictionary.bytes.len(),
+        args[2]
+    );
+    Ok(())
+}